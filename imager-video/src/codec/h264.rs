@@ -5,15 +5,16 @@ use std::convert::AsRef;
 use std::path::{PathBuf, Path};
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int};
-use std::collections::{VecDeque, HashMap, BTreeMap};
+use std::collections::{VecDeque, HashMap};
 use libc::{size_t, c_float, c_void, fread};
 use rayon::prelude::*;
 use x264_dev::{raw, sys};
 use itertools::Itertools;
 use serde::{Serialize, Deserialize};
 
-use crate::data::{Yuv420P, VideoBuffer};
+use crate::data::{Yuv420P, Frame, PixelFormat, ChromaLayout, VideoBuffer, Timing, Rational};
 use crate::tool::classifier::{self, Class};
+use crate::tool::scene::Shot;
 
 
 
@@ -21,10 +22,160 @@ use crate::tool::classifier::{self, Class};
 // DATA TYPES
 ///////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone)]
-pub enum Mode {
-    Speed,
-    Quality,
+/// x264 settings shared by the encoder and the optimizer.
+///
+/// The `Option` fields fall back to whatever the preset (and profile)
+/// picks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct H264Options {
+    pub preset: String,
+    pub tune: Option<String>,
+    pub profile: String,
+    /// E.g. `"3.1"` or `"4.1"`.
+    pub level: Option<String>,
+    /// Maximum keyframe interval, in frames.
+    pub keyint: Option<u32>,
+    pub bframes: Option<u32>,
+    pub ref_frames: Option<u32>,
+    /// In kbit/s.
+    pub vbv_maxrate: Option<u32>,
+    /// In kbit.
+    pub vbv_bufsize: Option<u32>,
+    /// `None` lets x264 decide.
+    pub threads: Option<u32>,
+    /// Raw `x264_param_parse` key/value pairs, applied before the fields
+    /// above.
+    pub extra_params: Vec<(String, String)>,
+}
+
+impl Default for H264Options {
+    fn default() -> Self {
+        H264Options::quality()
+    }
+}
+
+impl H264Options {
+    pub fn quality() -> Self {
+        let extra_params = vec![
+            // CPU FLAGS
+            ("non-deterministic", "1"),
+            // FRAME-TYPE
+            ("partitions", "all"),
+            ("constrained-intra", "1"),
+            ("deblock", "0,0"),
+            // RATECONTROL
+            ("qcomp", "0.5"),
+            ("aq-mode", "2"),
+            ("cplxblur", "20.0"),
+            // ANALYSIS
+            ("trellis", "2"),
+            ("subme", "11"),
+            ("psy-rd", "2.0:0.7"),
+            ("direct", "none"),
+            ("cqm", "flat"),
+            ("no-weightb", "1"),
+            ("no-mixed-refs", "1"),
+            ("no-dct-decimate", "1"),
+        ];
+        H264Options {
+            preset: String::from("medium"),
+            tune: Some(String::from("ssim")),
+            profile: String::from("high"),
+            level: None,
+            keyint: None,
+            bframes: None,
+            ref_frames: None,
+            vbv_maxrate: None,
+            vbv_bufsize: None,
+            threads: None,
+            extra_params: extra_params
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+        }
+    }
+    pub fn speed() -> Self {
+        H264Options {
+            preset: String::from("ultrafast"),
+            extra_params: Vec::new(),
+            ..H264Options::quality()
+        }
+    }
+    /// Baseline profile at level 3.1, for older phones and hardware
+    /// decoders.
+    pub fn mobile() -> Self {
+        // ONLY WHAT BASELINE CAN CARRY, `x264_param_apply_profile` TURNS OFF
+        // CABAC, 8X8 TRANSFORMS, B-FRAMES AND WEIGHTED PREDICTION ANYWAY
+        let extra_params = vec![
+            // CPU FLAGS
+            ("non-deterministic", "1"),
+            // FRAME-TYPE
+            ("partitions", "p8x8,i4x4"),
+            ("deblock", "0,0"),
+            // RATECONTROL
+            ("qcomp", "0.5"),
+            ("aq-mode", "2"),
+            // ANALYSIS
+            ("trellis", "1"),
+            ("subme", "9"),
+        ];
+        H264Options {
+            profile: String::from("baseline"),
+            level: Some(String::from("3.1")),
+            keyint: Some(250),
+            bframes: Some(0),
+            ref_frames: Some(3),
+            vbv_maxrate: Some(10_000),
+            vbv_bufsize: Some(10_000),
+            extra_params: extra_params
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            ..H264Options::quality()
+        }
+    }
+}
+
+/// Either one of the named option sets (`quality`, `speed`, `mobile`), or an
+/// x264 preset name on top of the `quality` defaults.
+impl std::str::FromStr for H264Options {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const X264_PRESETS: &[&str] = &[
+            "ultrafast",
+            "superfast",
+            "veryfast",
+            "faster",
+            "fast",
+            "medium",
+            "slow",
+            "slower",
+            "veryslow",
+            "placebo",
+        ];
+        match s.to_lowercase().as_str() {
+            "quality" => Ok(H264Options::quality()),
+            "speed" => Ok(H264Options::speed()),
+            "mobile" => Ok(H264Options::mobile()),
+            x if X264_PRESETS.contains(&x) => Ok(H264Options {
+                preset: x.to_owned(),
+                ..H264Options::quality()
+            }),
+            _ => Err(format!("unknown preset: {}", s)),
+        }
+    }
+}
+
+/// Settings for the CRF search.
+#[derive(Debug, Clone, Default)]
+pub struct OptOptions {
+    pub h264: H264Options,
+    /// Overrides the per-class VMAF thresholds.
+    pub target_vmaf: Option<f64>,
+    /// Dump the chosen sample encode of every shot, and the shot reports
+    /// (`shots.json`), here.
+    pub debug_dir: Option<PathBuf>,
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -50,52 +201,80 @@ fn c_str(s: &str) -> CString {
 
 
 ///////////////////////////////////////////////////////////////////////////////
-// GLOBAL SETTINGS
+// HELPERS
 ///////////////////////////////////////////////////////////////////////////////
 
-pub const SYSTEM_MODE: Mode = Mode::Quality;
+/// `x264_param_parse` status for unknown keys (`X264_PARAM_BAD_VALUE`, i.e.
+/// `-2`, is anything else).
+const X264_PARAM_BAD_NAME: i32 = -1;
 
-///////////////////////////////////////////////////////////////////////////////
-// HELPERS
-///////////////////////////////////////////////////////////////////////////////
+unsafe fn apply(param: &mut sys::X264ParamT, key: &str, value: &str) -> Result<(), String> {
+    let c_key = c_str(key);
+    let c_value = c_str(value);
+    let status = sys::x264_param_parse(param, c_key.as_ptr(), c_value.as_ptr());
+    match status {
+        0 => Ok(()),
+        X264_PARAM_BAD_NAME => Err(format!("unknown x264 option: {}", key)),
+        _ => Err(format!("invalid value for x264 option {}: {}", key, value)),
+    }
+}
 
-unsafe fn apply(param: &mut sys::X264ParamT, key: &str, value: &str) {
-    let key = c_str(key);
-    let value = c_str(value);
-    assert!(sys::x264_param_parse(param, key.as_ptr(), value.as_ptr()) == 0);
-    std::mem::forget(key);
-    std::mem::forget(value);
+/// x264 rejects profiles that can't carry the input, e.g. `high` with 10-bit
+/// samples, so pick the lowest one that can.
+fn profile_for(format: PixelFormat, profile: &str) -> String {
+    let compatible: &[&str] = match (format.chroma, format.is_high_depth()) {
+        (ChromaLayout::Yuv420, false) => return profile.to_owned(),
+        (ChromaLayout::Yuv420, true) => &["high10", "high422", "high444"],
+        (ChromaLayout::Yuv422, _) => &["high422", "high444"],
+        (ChromaLayout::Yuv444, _) => &["high444"],
+    };
+    if compatible.contains(&profile) {
+        profile.to_owned()
+    } else {
+        compatible[0].to_owned()
+    }
+}
+
+fn x264_csp(format: PixelFormat) -> i32 {
+    let csp = match format.chroma {
+        ChromaLayout::Yuv420 => raw::X264_CSP_I420,
+        ChromaLayout::Yuv422 => raw::X264_CSP_I422,
+        ChromaLayout::Yuv444 => raw::X264_CSP_I444,
+    };
+    if format.is_high_depth() {
+        (csp | raw::X264_CSP_HIGH_DEPTH) as i32
+    } else {
+        csp as i32
+    }
 }
 
-unsafe fn new_param(width: u32, height: u32) -> sys::X264ParamT {
+unsafe fn new_param(
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    options: &H264Options,
+) -> Result<sys::X264ParamT, String> {
     // INIT PARAM
     let mut param: sys::X264ParamT = unsafe {std::mem::zeroed()};
-    let profile = CString::new("high").expect("CString failed");
-    let tune = {
-        let opt1 = "film";
-        let opt2 = "animation";
-        let opt3 = "grain";
-        let opt4 = "ssim";
-        CString::new(opt4).expect("CString failed")
-    };
-    let preset = match SYSTEM_MODE {
-        Mode::Quality => {
-            let extreme = false;
-            if extreme {
-                CString::new("placebo").expect("CString failed")
-            } else {
-                CString::new("medium").expect("CString failed")
-            }
-        },
-        Mode::Speed => CString::new("ultrafast").expect("CString failed"),
+    let profile = c_str(&profile_for(format, &options.profile));
+    let preset = c_str(&options.preset);
+    let tune = options.tune.as_ref().map(|x| c_str(x));
+    {
+        let status = sys::x264_param_default_preset(
+            &mut param,
+            preset.as_ptr(),
+            tune.as_ref().map(|x| x.as_ptr()).unwrap_or(std::ptr::null()),
+        );
+        if status != 0 {
+            return Err(format!(
+                "invalid x264 preset or tune: {} {:?}",
+                options.preset,
+                options.tune,
+            ));
+        }
     };
-    assert!(sys::x264_param_default_preset(
-        &mut param,
-        preset.as_ptr(),
-        tune.as_ptr(),
-    ) == 0);
-    param.i_bitdepth = 8;
-    param.i_csp = raw::X264_CSP_I420 as i32;
+    param.i_bitdepth = format.bit_depth as i32;
+    param.i_csp = x264_csp(format);
     param.i_width  = width as i32;
     param.i_height = height as i32;
     param.b_vfr_input = 0;
@@ -105,436 +284,738 @@ unsafe fn new_param(width: u32, height: u32) -> sys::X264ParamT {
     // DEBUGGING
     param.i_log_level = 1;
 
-    // CPU FLAGS
-    apply(&mut param, "non-deterministic", "1");
-
-    // FRAME-TYPE
-    apply(&mut param, "partitions", "all");
-    apply(&mut param, "constrained-intra", "1");
-    apply(&mut param, "deblock", "0,0");
-
-    // RATECONTROL
-    // apply(&mut param, "crf", crf);
-    apply(&mut param, "qcomp", "0.5");
-    apply(&mut param, "aq-mode", "2");
-    apply(&mut param, "cplxblur", "20.0");
-
-    // ANALYSIS
-    apply(&mut param, "trellis", "2");
-    apply(&mut param, "subme", "11");
-    apply(&mut param, "psy-rd", "2.0:0.7");
-    apply(&mut param, "direct", "none");
-    apply(&mut param, "cqm", "flat");
-    apply(&mut param, "no-weightb", "1");
-    apply(&mut param, "no-mixed-refs", "1");
-    apply(&mut param, "no-dct-decimate", "1");
-
-    // FRAME-TYPE
-    apply(&mut param, "partitions", "all");
-    apply(&mut param, "constrained-intra", "1");
-    apply(&mut param, "deblock", "0,0");
+    // EXTRA
+    for (key, value) in options.extra_params.iter() {
+        apply(&mut param, key, value)?;
+    }
+
+    // OPTIONS
+    if let Some(level) = options.level.as_ref() {
+        apply(&mut param, "level", level)?;
+    }
+    if let Some(keyint) = options.keyint {
+        apply(&mut param, "keyint", &format!("{}", keyint))?;
+    }
+    if let Some(bframes) = options.bframes {
+        apply(&mut param, "bframes", &format!("{}", bframes))?;
+    }
+    if let Some(ref_frames) = options.ref_frames {
+        apply(&mut param, "ref", &format!("{}", ref_frames))?;
+    }
+    if let Some(vbv_maxrate) = options.vbv_maxrate {
+        apply(&mut param, "vbv-maxrate", &format!("{}", vbv_maxrate))?;
+    }
+    if let Some(vbv_bufsize) = options.vbv_bufsize {
+        apply(&mut param, "vbv-bufsize", &format!("{}", vbv_bufsize))?;
+    }
+    if let Some(threads) = options.threads {
+        apply(&mut param, "threads", &format!("{}", threads))?;
+    }
 
     // FINALIZE
     {
         let status = sys::x264_param_apply_profile(&mut param, profile.as_ptr());
-        assert!(status == 0);
+        if status != 0 {
+            return Err(format!("invalid x264 profile: {}", options.profile));
+        }
     };
 
-    // TODO
-    std::mem::forget(preset);
-    std::mem::forget(tune);
-    std::mem::forget(profile);
-
     // DONE
-    param
+    Ok(param)
+}
+
+unsafe fn apply_timing(param: &mut sys::X264ParamT, timing: &Timing) {
+    let frame_rate = timing.frame_rate();
+    let variable = timing.pts
+        .windows(2)
+        .map(|x| x[1] - x[0])
+        .dedup()
+        .count() > 1;
+    param.i_fps_num = frame_rate.num as u32;
+    param.i_fps_den = frame_rate.den as u32;
+    param.i_timebase_num = timing.time_base.num as u32;
+    param.i_timebase_den = timing.time_base.den as u32;
+    param.b_vfr_input = variable as i32;
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-// REPORTING / METADATA
+// ENCODED OUTPUT
 ///////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct FrameReport {
-    index: usize,
-    vmaf: f64,
-    class: Class,
-    start_pos: u8,
-    crf: u8,
+/// A single encoded frame (Annex-B NAL units), in decoding order.
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    pub pts: i64,
+    pub dts: i64,
+    pub keyframe: bool,
+}
+
+/// Encoder output, with everything a muxer needs.
+#[derive(Debug, Clone)]
+pub struct EncodedVideo {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    /// Timing of the source, packet timestamps use the same time base.
+    pub timing: Timing,
+    /// SPS and PPS, as Annex-B NAL units.
+    pub headers: Vec<u8>,
+    pub packets: Vec<Packet>,
+}
+
+impl EncodedVideo {
+    /// Raw Annex-B bytestream, i.e. the contents of a `.h264` file.
+    pub fn to_annexb(&self) -> Vec<u8> {
+        self.packets
+            .iter()
+            .flat_map(|x| x.data.iter().cloned())
+            .collect()
+    }
+}
+
+unsafe fn encoder_headers(encoder_ctx: *mut sys::X264T) -> Vec<u8> {
+    let mut p_nal: *mut sys::X264NalT = std::ptr::null_mut();
+    let mut i_nal: i32 = 0;
+    let size = sys::x264_encoder_headers(encoder_ctx, &mut p_nal, &mut i_nal);
+    assert!(size >= 0);
+    // ALL NAL PAYLOADS ARE SEQUENTIAL IN MEMORY
+    std::slice::from_raw_parts((*p_nal).p_payload, size as usize).to_vec()
 }
 
+unsafe fn to_packet(
+    i_frame_size: i32,
+    p_nal: *mut sys::X264NalT,
+    picture_output: &sys::X264PictureT,
+) -> Option<Packet> {
+    assert!(i_frame_size >= 0);
+    if i_frame_size == 0 {
+        return None;
+    }
+    let data = std::slice::from_raw_parts(
+        (*p_nal).p_payload,
+        i_frame_size as usize,
+    );
+    Some(Packet {
+        data: data.to_vec(),
+        pts: picture_output.i_pts,
+        dts: picture_output.i_dts,
+        keyframe: picture_output.b_keyframe != 0,
+    })
+}
 
 ///////////////////////////////////////////////////////////////////////////////
-// LOW-LEVEL ENCODER
+// ENCODER CONTEXT
 ///////////////////////////////////////////////////////////////////////////////
 
-pub unsafe fn encode(stream: &VideoBuffer, crf: f32) -> Result<Vec<u8>, String> {
-    ///////////////////////////////////////////////////////////////////////////
-    // SETUP
-    ///////////////////////////////////////////////////////////////////////////
-    let (width, height) = stream.dimensions();
-    let luma_size = width * height;
-    let chroma_size = luma_size / 4;
-    ///////////////////////////////////////////////////////////////////////////
-    // INIT PARAM
-    ///////////////////////////////////////////////////////////////////////////
-    let mut param: sys::X264ParamT = new_param(width, height);
-    apply(&mut param, "crf", &format!("{}", crf));
-    ///////////////////////////////////////////////////////////////////////////
-    // INIT PICTURE
-    ///////////////////////////////////////////////////////////////////////////
-    let mut picture_param = new_param(width, height);
-    let mut picture: sys::X264PictureT = std::mem::zeroed();
-    let mut picture_output: sys::X264PictureT = std::mem::zeroed();
-    {
-        let status = sys::x264_picture_alloc(
-            &mut picture,
-            param.i_csp,
-            param.i_width,
-            param.i_height
+/// Incremental x264 encoder, frames are copied in one at a time.
+pub struct Encoder {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    encoder_ctx: *mut sys::X264T,
+    picture: sys::X264PictureT,
+    picture_output: sys::X264PictureT,
+    headers: Vec<u8>,
+    packets: Vec<Packet>,
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.encoder_ctx.is_null() {
+                sys::x264_encoder_close(self.encoder_ctx);
+                sys::x264_picture_clean(&mut self.picture);
+                self.encoder_ctx = std::ptr::null_mut();
+            }
+        };
+    }
+}
+
+impl Encoder {
+    /// The format must match the one given to `new_param`.
+    pub unsafe fn open(mut param: sys::X264ParamT, format: PixelFormat) -> Self {
+        let (width, height) = (param.i_width as u32, param.i_height as u32);
+        // INIT PICTURE
+        let mut picture: sys::X264PictureT = std::mem::zeroed();
+        let picture_output: sys::X264PictureT = std::mem::zeroed();
+        {
+            let status = sys::x264_picture_alloc(
+                &mut picture,
+                param.i_csp,
+                param.i_width,
+                param.i_height
+            );
+            assert!(status == 0);
+        };
+        // ENCODER CONTEXT
+        let encoder_ctx: *mut sys::X264T = sys::x264_encoder_open(&mut param);
+        assert!(!encoder_ctx.is_null());
+        assert!(picture.img.i_plane == 3);
+        assert!(picture.param.is_null());
+        // DONE
+        Encoder {
+            width,
+            height,
+            format,
+            encoder_ctx,
+            picture,
+            picture_output,
+            headers: encoder_headers(encoder_ctx),
+            packets: Vec::new(),
+        }
+    }
+    /// The optional param applies to this frame only (e.g. a per-frame CRF).
+    pub unsafe fn push(
+        &mut self,
+        source: &Yuv420P,
+        pts: i64,
+        picture_param: Option<&mut sys::X264ParamT>,
+    ) {
+        assert!(self.format == PixelFormat::YUV420P);
+        assert!(source.dimensions() == (self.width, self.height));
+        self.encode_planes([source.y(), source.u(), source.v()], pts, picture_param);
+    }
+    pub unsafe fn push_frame(
+        &mut self,
+        source: &Frame,
+        pts: i64,
+        picture_param: Option<&mut sys::X264ParamT>,
+    ) {
+        assert!(source.format == self.format);
+        assert!(source.dimensions() == (self.width, self.height));
+        self.encode_planes(
+            [source.plane(0), source.plane(1), source.plane(2)],
+            pts,
+            picture_param,
         );
-        assert!(status == 0);
-    };
-    ///////////////////////////////////////////////////////////////////////////
-    // ENCODER CONTEXT
-    ///////////////////////////////////////////////////////////////////////////
-    let mut encoder_ctx: *mut sys::X264T = sys::x264_encoder_open(&mut param);
-    assert!(!encoder_ctx.is_null());
-    assert!(picture.img.i_plane == 3);
-    assert!(picture.img.i_stride[0] == width as i32);
-    assert!(picture.img.i_stride[1] == (width / 2) as i32);
-    assert!(picture.img.i_stride[2] == (width / 2) as i32);
-    assert!(picture.param.is_null());
-    // picture.param = &mut picture_param;
-    ///////////////////////////////////////////////////////////////////////////
-    // ???
-    ///////////////////////////////////////////////////////////////////////////
-    let mut p_nal: *mut sys::X264NalT = std::ptr::null_mut();
-    let mut i_nal: i32 = std::mem::zeroed();
-    ///////////////////////////////////////////////////////////////////////////
-    // ENCODED OUTPUT
-    ///////////////////////////////////////////////////////////////////////////
-    let mut output = Vec::<u8>::new();
-    ///////////////////////////////////////////////////////////////////////////
-    // GO!
-    ///////////////////////////////////////////////////////////////////////////
-    for source in stream.as_frames() {
-        let (mut y_ptr, mut u_ptr, mut v_ptr) = unsafe {(
-            std::slice::from_raw_parts_mut(picture.img.plane[0], luma_size as usize),
-            std::slice::from_raw_parts_mut(picture.img.plane[1], chroma_size as usize),
-            std::slice::from_raw_parts_mut(picture.img.plane[2], chroma_size as usize),
-        )};
-        y_ptr.copy_from_slice(&source.y());
-        u_ptr.copy_from_slice(&source.u());
-        v_ptr.copy_from_slice(&source.v());
+    }
+    unsafe fn encode_planes(
+        &mut self,
+        planes: [&[u8]; 3],
+        pts: i64,
+        picture_param: Option<&mut sys::X264ParamT>,
+    ) {
+        // BUFFER - ROW BY ROW, X264 MAY PAD ITS ROWS
+        for (ix, plane) in planes.iter().enumerate() {
+            let (width, height) = self.format.plane_dimensions((self.width, self.height), ix);
+            let row_size = width as usize * self.format.bytes_per_sample();
+            let stride = self.picture.img.i_stride[ix] as usize;
+            assert!(plane.len() == row_size * height as usize);
+            for (row, source) in plane.chunks(row_size).enumerate() {
+                let output = std::slice::from_raw_parts_mut(
+                    self.picture.img.plane[ix].add(row * stride),
+                    row_size,
+                );
+                output.copy_from_slice(source);
+            }
+        }
         // PICTURE SETTINGS
-        // apply(&mut picture_param, "crf", &format!("{}", crf));
+        self.picture.i_pts = pts;
+        self.picture.param = match picture_param {
+            Some(x) => x as *mut sys::X264ParamT,
+            None => std::ptr::null_mut(),
+        };
         // ENCODE
+        let mut p_nal: *mut sys::X264NalT = std::ptr::null_mut();
+        let mut i_nal: i32 = 0;
         let i_frame_size = sys::x264_encoder_encode(
-            encoder_ctx,
+            self.encoder_ctx,
             &mut p_nal,
             &mut i_nal,
-            &mut picture,
-            &mut picture_output,
+            &mut self.picture,
+            &mut self.picture_output,
         );
-        assert!(i_frame_size >= 0);
-        if i_frame_size > 0 {
-            let encoded = std::slice::from_raw_parts(
-                (*p_nal).p_payload,
-                i_frame_size as usize,
+        self.picture.param = std::ptr::null_mut();
+        self.packets.extend(to_packet(i_frame_size, p_nal, &self.picture_output));
+    }
+    /// Flush delayed frames and close the encoder.
+    pub unsafe fn finish(mut self, timing: Timing) -> EncodedVideo {
+        // FLUSH DELAYED FRAMES
+        let mut p_nal: *mut sys::X264NalT = std::ptr::null_mut();
+        let mut i_nal: i32 = 0;
+        while sys::x264_encoder_delayed_frames(self.encoder_ctx) > 0 {
+            let i_frame_size = sys::x264_encoder_encode(
+                self.encoder_ctx,
+                &mut p_nal,
+                &mut i_nal,
+                std::ptr::null_mut(),
+                &mut self.picture_output,
             );
-            output.extend_from_slice(encoded);
+            self.packets.extend(to_packet(i_frame_size, p_nal, &self.picture_output));
+        }
+        // DONE
+        EncodedVideo {
+            width: self.width,
+            height: self.height,
+            format: self.format,
+            timing,
+            headers: std::mem::replace(&mut self.headers, Vec::new()),
+            packets: std::mem::replace(&mut self.packets, Vec::new()),
         }
     }
-    ///////////////////////////////////////////////////////////////////////////
-    // FLUSH DELAYED FRAMES
-    ///////////////////////////////////////////////////////////////////////////
-    while sys::x264_encoder_delayed_frames(encoder_ctx) > 0 {
-        let i_frame_size = sys::x264_encoder_encode(
-            encoder_ctx,
-            &mut p_nal,
-            &mut i_nal,
-            std::ptr::null_mut(),
-            &mut picture_output,
-        );
-        assert!(i_frame_size >= 0);
-        if i_frame_size > 0 {
-            let encoded = std::slice::from_raw_parts(
-                (*p_nal).p_payload,
-                i_frame_size as usize,
-            );
-            output.extend_from_slice(encoded);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// LOW-LEVEL ENCODER
+///////////////////////////////////////////////////////////////////////////////
+
+pub unsafe fn encode(stream: &VideoBuffer, crf: f32, options: &H264Options) -> Result<Vec<u8>, String> {
+    encode_video(stream, crf, options).map(|x| x.to_annexb())
+}
+
+pub unsafe fn encode_video(
+    stream: &VideoBuffer,
+    crf: f32,
+    options: &H264Options,
+) -> Result<EncodedVideo, String> {
+    // INIT PARAM
+    let (width, height) = stream.dimensions();
+    let mut param: sys::X264ParamT = new_param(width, height, PixelFormat::YUV420P, options)?;
+    apply_timing(&mut param, stream.timing());
+    apply(&mut param, "crf", &format!("{}", crf))?;
+    // GO!
+    let mut encoder = Encoder::open(param, PixelFormat::YUV420P);
+    for (index, source) in stream.as_frames().iter().enumerate() {
+        encoder.push(source, stream.timing().pts[index], None);
+    }
+    // DONE
+    Ok(encoder.finish(stream.timing().clone()))
+}
+
+/// Encode frames as they arrive (e.g. from `FrameReader::timed`), without
+/// holding the decoded stream in memory. Timestamps are in `time_base`
+/// ticks, and may be variable.
+pub unsafe fn encode_stream<I>(
+    frames: I,
+    time_base: Rational,
+    crf: f32,
+    options: &H264Options,
+) -> Result<EncodedVideo, String>
+where
+    I: Iterator<Item = (Yuv420P, i64)>
+{
+    encode_frames(frames.map(|(x, pts)| (Frame::from(x), pts)), time_base, crf, options)
+}
+
+/// Like `encode_stream`, but keeps the pixel format of the first frame,
+/// e.g. 10-bit or 4:4:4 output from `FrameReader::next_timed_frame`.
+pub unsafe fn encode_frames<I>(
+    frames: I,
+    time_base: Rational,
+    crf: f32,
+    options: &H264Options,
+) -> Result<EncodedVideo, String>
+where
+    I: Iterator<Item = (Frame, i64)>
+{
+    // SETUP
+    let mut frames = frames;
+    let head = frames.by_ref().take(2).collect::<Vec<_>>();
+    let (dimensions, format) = {
+        let (first, _) = head
+            .first()
+            .ok_or(String::from("empty video stream"))?;
+        (first.dimensions(), first.format)
+    };
+    let (width, height) = dimensions;
+    // THE NOMINAL FRAME RATE, FROM THE FIRST FRAME DURATION
+    let frame_duration = match head.as_slice() {
+        [(_, a), (_, b)] => (b - a).max(1),
+        _ => 1,
+    };
+    // INIT PARAM
+    let mut param: sys::X264ParamT = new_param(width, height, format, options)?;
+    param.i_fps_num = time_base.den as u32;
+    param.i_fps_den = (time_base.num as i64 * frame_duration) as u32;
+    param.i_timebase_num = time_base.num as u32;
+    param.i_timebase_den = time_base.den as u32;
+    param.b_vfr_input = 1;
+    apply(&mut param, "crf", &format!("{}", crf))?;
+    // GO!
+    let mut encoder = Encoder::open(param, format);
+    let mut pts = Vec::<i64>::new();
+    let mut last_duration = frame_duration;
+    for (source, x) in head.into_iter().chain(frames) {
+        if source.dimensions() != dimensions || source.format != format {
+            return Err(String::from("frame dimensions or format changed mid-stream"));
         }
+        if let Some(last) = pts.last() {
+            last_duration = (x - last).max(1);
+        }
+        encoder.push_frame(&source, x, None);
+        pts.push(x);
     }
-    ///////////////////////////////////////////////////////////////////////////
-    // CLEANUP
-    ///////////////////////////////////////////////////////////////////////////
-    sys::x264_encoder_close(encoder_ctx);
-    sys::x264_picture_clean(&mut picture);
-    ///////////////////////////////////////////////////////////////////////////
     // DONE
-    ///////////////////////////////////////////////////////////////////////////
-    Ok(output)
+    let end_pts = pts.last().map(|x| x + last_duration).unwrap_or(0);
+    Ok(encoder.finish(Timing {time_base, pts, end_pts}))
 }
 
 ///////////////////////////////////////////////////////////////////////////////
-// DEV - PICTURE OPT
+// QUALITY THRESHOLDS
 ///////////////////////////////////////////////////////////////////////////////
 
+/// x264's CRF range for 8-bit input.
+const MAX_CRF: u8 = 51;
+
+/// Used when nothing meets the threshold, i.e. the best quality x264 gives
+/// us under the configured profile.
+pub const FALLBACK_CRF: u8 = 0;
 
-pub unsafe fn opt_frame(index: usize, source: Yuv420P) -> (Vec<u8>, FrameReport) {
-    let class_report = classifier::get_report(&source.to_rgba_image());
-    let is_4k = (source.width * source.height) >= (3840 * 2160);
-    let is_hd = (source.width * source.height) >= (1280 * 720);
-    let source_video = VideoBuffer::singleton(source);
+fn is_hd((width, height): (u32, u32)) -> bool {
+    (width * height) >= (1280 * 720)
+}
+
+/// Minimum VMAF score for the given content class.
+fn vmaf_threshold(class: &Class, is_hd: bool) -> f64 {
+    match class {
+        Class::L0 | Class::L1 | Class::L2 => {
+            if is_hd {80.0} else {96.0}
+        }
+        Class::M1 => {
+            if is_hd {60.0} else {70.0}
+        }
+        Class::H1 | Class::H2 => {
+            if is_hd {30.0} else {60.0}
+        }
+    }
+}
+
+fn target_vmaf(options: &OptOptions, class: &Class, is_hd: bool) -> f64 {
+    options.target_vmaf.unwrap_or_else(|| vmaf_threshold(class, is_hd))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// SCENE OPT
+///////////////////////////////////////////////////////////////////////////////
+
+/// Length (in frames) of the contiguous window that is encoded and scored
+/// for each shot.
+pub const SAMPLE_WINDOW: usize = 24;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShotReport {
+    pub shot: Shot,
+    pub vmaf: f64,
+    pub class: Class,
+    pub crf: u8,
+    /// `false` if even the fallback CRF didn't meet the VMAF threshold.
+    pub passed: bool,
+}
+
+/// A contiguous run of frames from the middle of the shot, so inter-frame
+/// prediction is part of what gets scored.
+fn sample_range(shot: &Shot) -> std::ops::Range<usize> {
+    let length = shot.len().min(SAMPLE_WINDOW);
+    let start = shot.start + (shot.len() - length) / 2;
+    start .. start + length
+}
+
+/// The encoded sample and its VMAF score.
+unsafe fn score_sample(
+    sample: &VideoBuffer,
+    crf: u8,
+    options: &H264Options,
+) -> Result<(Vec<u8>, f64), String> {
+    let encoded = encode(sample, crf as f32, options)?;
+    let ref_video = VideoBuffer::load_from_memory(&encoded)
+        .map_err(|_| format!("failed to decode the crf={} sample", crf))?;
+    let vmaf = crate::tool::vmaf::get_report(sample, &ref_video);
+    Ok((encoded, vmaf))
+}
+
+/// Bisect for the highest CRF whose sample still meets the VMAF threshold.
+/// Takes the frames rather than a `VideoBuffer`, so shots can be searched in
+/// parallel.
+pub unsafe fn opt_shot(
+    frames: &[Yuv420P],
+    time_base: Rational,
+    shot: Shot,
+    options: &OptOptions,
+) -> Result<ShotReport, String> {
+    let sample = frames[sample_range(&shot)].to_vec();
+    opt_sample(sample, time_base, shot, options)
+}
+
+/// `opt_shot`, given just the frames of the shot's `sample_range`.
+unsafe fn opt_sample(
+    frames: Vec<Yuv420P>,
+    time_base: Rational,
+    shot: Shot,
+    options: &OptOptions,
+) -> Result<ShotReport, String> {
+    // SETUP
+    let timing = Timing::constant(frames.len(), time_base);
+    let sample = VideoBuffer::from_frames(frames, timing)
+        .map_err(|_| String::from("empty shot"))?;
+    let key_frame = &sample.as_frames()[sample.as_frames().len() / 2];
+    let class = classifier::get_report(&key_frame.to_rgba_image()).class;
+    let threshold = target_vmaf(options, &class, is_hd(key_frame.dimensions()));
     // GO!
-    let term = |vmaf_report: f64| -> bool {
-        match class_report.class {
-            Class::L0 | Class::L1 | Class::L2 => {
-                if is_hd {
-                    vmaf_report >= 80.0
-                } else {
-                    vmaf_report >= 96.0
-                }
-            }
-            Class::M1 => {
-                if is_hd {
-                    vmaf_report >= 60.0
-                } else {
-                    vmaf_report >= 70.0
-                }
-            }
-            Class::H1 | Class::H2 => {
-                if is_hd {
-                    vmaf_report >= 30.0
-                } else {
-                    vmaf_report >= 60.0
-                }
-            }
+    let (mut low, mut high) = (0, MAX_CRF);
+    let mut best: Option<(u8, Vec<u8>, f64)> = None;
+    while low <= high {
+        let crf = low + (high - low) / 2;
+        let (encoded, vmaf) = score_sample(&sample, crf, &options.h264)?;
+        if vmaf >= threshold {
+            best = Some((crf, encoded, vmaf));
+            low = crf + 1;
+        } else if crf == 0 {
+            break;
+        } else {
+            high = crf - 1;
         }
-    };
-    let starting_pos = || {
-        let run = |q| -> bool {
-            let encoded = encode(&source_video, q as f32).expect("encode yuv420p");
-            let ref_video = VideoBuffer::load_from_memory(&encoded).expect("reconstruct");
-            let vmaf_report = crate::tool::vmaf::get_report(&source_video, &ref_video);
-            term(vmaf_report)
-        };
-        let reduce_starting_values = |qs: Vec<u8>| -> Option<u8> {
-            let mut result: Option<u8> = None;
-            for q in qs.into_iter() {
-                let passed = run(q);
-                if !passed {
-                    assert!(result.is_none());
-                    result = Some(q);
-                    break;
-                }
-            }
-            result
-        };
-        let bad_fallback = || reduce_starting_values(vec![
-            10,
-            30,
-            50,
-            60,
-        ]);
-        match class_report.class {
-            // TODO
-            // ...
-            _ => bad_fallback()
+    }
+    // NOTHING PASSED
+    let passed = best.is_some();
+    let (crf, encoded, vmaf) = match best {
+        Some(x) => x,
+        None => {
+            let (encoded, vmaf) = score_sample(&sample, FALLBACK_CRF, &options.h264)?;
+            (FALLBACK_CRF, encoded, vmaf)
         }
     };
-    let start_pos = starting_pos().unwrap_or(0);
-    for crf in (0 .. start_pos).rev().filter(|x| x % 2 == 0) {
-        let encoded = encode(&source_video, crf as f32).expect("encode yuv420p");
-        let ref_video = VideoBuffer::load_from_memory(&encoded).expect("reconstruct");
-        let vmaf_report = crate::tool::vmaf::get_report(&source_video, &ref_video);
-        if term(vmaf_report) {
-            // return (crf, vmaf_report, class_report.class.clone(), encoded);
-            let meta = FrameReport{
-                index,
-                vmaf: vmaf_report,
-                class: class_report.class.clone(),
-                start_pos: start_pos,
-                crf: crf,
-            };
-            return (encoded, meta);
+    // DEBUG - THE CHOSEN SAMPLE ENCODE
+    if let Some(debug_dir) = options.debug_dir.as_ref() {
+        let path = debug_dir.join(format!(
+            "shot={start}-{end}--crf={crf}--vmaf={vmaf:.1}--cls={cls}.h264",
+            start=shot.start,
+            end=shot.end,
+            crf=crf,
+            vmaf=vmaf,
+            cls=class,
+        ));
+        if let Err(e) = std::fs::write(&path, encoded) {
+            eprintln!("failed to write debug sample {:?}: {}", path, e);
+        }
+    }
+    // DONE
+    Ok(ShotReport {shot, vmaf, class, crf, passed})
+}
+
+/// Every shot report, when there is a debug dir.
+fn save_debug_report(options: &OptOptions, shots_report: &[ShotReport]) {
+    if let Some(debug_dir) = options.debug_dir.as_ref() {
+        let path = debug_dir.join("shots.json");
+        let json = serde_json::to_string_pretty(shots_report).expect("to json str");
+        if let Err(e) = std::fs::write(&path, json) {
+            eprintln!("failed to write debug report {:?}: {}", path, e);
         }
     }
-    // FALLBACK
-    unimplemented!()
 }
 
-pub fn opt_frames(stream: &VideoBuffer) -> BTreeMap<usize, FrameReport> {
-    let frames_meta = stream
-        .as_frames()
-        .clone()
-        .into_iter()
-        .enumerate()
-        .collect::<Vec<_>>()
+/// `progress` is called as every shot finishes, in no particular order.
+pub fn opt_shots<P>(
+    stream: &VideoBuffer,
+    options: &OptOptions,
+    progress: &P,
+) -> Result<Vec<ShotReport>, String>
+where
+    P: Fn(&ShotReport) + Sync,
+{
+    let frames = stream.as_frames();
+    let time_base = stream.timing().time_base;
+    crate::tool::scene::detect_shots(frames)
         .into_par_iter()
-        .map(|(index, source)| {
-            let (frame, frame_meta) = unsafe {
-                opt_frame(index, source.clone())
-            };
-            let path = format!(
-                "assets/output/debug/ix={ix}--q={qp}--vmaf={vmaf}--cls={cls}.h264",
-                ix=frame_meta.index,
-                qp=frame_meta.crf,
-                cls=frame_meta.class,
-                vmaf=pretty_float(frame_meta.vmaf),
-            );
-            std::fs::write(path, frame);
-            println!("opt frame: {}", index);
-            (index, frame_meta)
+        .map(|shot| {
+            let report = unsafe {opt_shot(frames, time_base, shot, options)}?;
+            progress(&report);
+            Ok(report)
         })
-        .collect::<BTreeMap<usize, FrameReport>>();
-    {
-        let json = serde_json::to_string_pretty(&frames_meta).expect("to json str");
-        std::fs::write("assets/output/test.json", json);
+        .collect::<Result<Vec<_>, String>>()
+}
+
+/// x264 `zones` value, frame ranges are inclusive.
+fn zones(shots: &[ShotReport]) -> String {
+    shots
+        .iter()
+        .map(|x| format!("{},{},crf={}", x.shot.start, x.shot.end - 1, x.crf))
+        .join("/")
+}
+
+/// The final encode, every shot at its own CRF.
+unsafe fn encode_zones<I>(
+    frames: I,
+    dimensions: (u32, u32),
+    timing: &Timing,
+    shots_report: &[ShotReport],
+    options: &OptOptions,
+) -> Result<EncodedVideo, String>
+where
+    I: Iterator,
+    I::Item: std::borrow::Borrow<Yuv420P>,
+{
+    use std::borrow::Borrow;
+    // INIT PARAM
+    let (width, height) = dimensions;
+    let mut param: sys::X264ParamT = new_param(width, height, PixelFormat::YUV420P, &options.h264)?;
+    apply_timing(&mut param, timing);
+    // THE BASE CRF ONLY MATTERS OUTSIDE OF ZONES
+    let base_crf = shots_report
+        .iter()
+        .map(|x| x.crf)
+        .min()
+        .ok_or(String::from("empty video stream"))?;
+    apply(&mut param, "crf", &format!("{}", base_crf))?;
+    apply(&mut param, "zones", &zones(shots_report))?;
+    // GO!
+    let mut encoder = Encoder::open(param, PixelFormat::YUV420P);
+    let mut total: usize = 0;
+    for (source, pts) in frames.zip(timing.pts.iter()) {
+        let source: &Yuv420P = source.borrow();
+        if source.dimensions() != dimensions {
+            return Err(String::from("frame dimensions changed mid-stream"));
+        }
+        encoder.push(source, *pts, None);
+        total = total + 1;
+    }
+    if total != timing.pts.len() {
+        return Err(String::from("the stream ended early"));
     }
-    frames_meta
+    // DONE
+    Ok(encoder.finish(timing.clone()))
 }
 
-pub unsafe fn opt_video(stream: &VideoBuffer) -> Result<Vec<u8>, ()> {
-    ///////////////////////////////////////////////////////////////////////////
-    // FRAME REPORT
-    ///////////////////////////////////////////////////////////////////////////
-    println!("total frames: {}", stream.as_frames().len());
-    let frames_report = opt_frames(stream);
+/// The shot search (and therefore the final encode) works on 8-bit 4:2:0
+/// frames. Check sources with `check_opt_format` first, anything else would
+/// be silently downconverted. `encode_frames` keeps other formats, at a
+/// fixed CRF.
+pub fn check_opt_format(format: PixelFormat) -> Result<(), String> {
+    if format == PixelFormat::YUV420P {
+        Ok(())
+    } else {
+        Err(format!("only yuv420p sources can be optimized, got {}", format))
+    }
+}
+
+/// 8-bit 4:2:0 only, see `check_opt_format`. See `opt_shots` for
+/// `progress`.
+pub unsafe fn opt_video<P>(
+    stream: &VideoBuffer,
+    options: &OptOptions,
+    progress: &P,
+) -> Result<(EncodedVideo, Vec<ShotReport>), String>
+where
+    P: Fn(&ShotReport) + Sync,
+{
     ///////////////////////////////////////////////////////////////////////////
-    // SETUP
+    // CHECK - REJECT BAD OPTIONS BEFORE THE SEARCH
     ///////////////////////////////////////////////////////////////////////////
     let (width, height) = stream.dimensions();
-    let luma_size = width * height;
-    let chroma_size = luma_size / 4;
+    new_param(width, height, PixelFormat::YUV420P, &options.h264)?;
     ///////////////////////////////////////////////////////////////////////////
-    // INIT PARAM
-    ///////////////////////////////////////////////////////////////////////////
-    let mut param: sys::X264ParamT = new_param(width, height);
-    // apply(&mut param, "crf", &format!("{}", crf));
+    // SHOT REPORT
     ///////////////////////////////////////////////////////////////////////////
-    // INIT PICTURE
+    let shots_report = opt_shots(stream, options, progress)?;
+    save_debug_report(options, &shots_report);
     ///////////////////////////////////////////////////////////////////////////
-    let mut picture_param = new_param(width, height);
-    let mut picture: sys::X264PictureT = std::mem::zeroed();
-    let mut picture_output: sys::X264PictureT = std::mem::zeroed();
-    {
-        let status = sys::x264_picture_alloc(
-            &mut picture,
-            param.i_csp,
-            param.i_width,
-            param.i_height
-        );
-        assert!(status == 0);
-    };
-    ///////////////////////////////////////////////////////////////////////////
-    // ENCODER CONTEXT
-    ///////////////////////////////////////////////////////////////////////////
-    let mut encoder_ctx: *mut sys::X264T = sys::x264_encoder_open(&mut param);
-    assert!(!encoder_ctx.is_null());
-    assert!(picture.img.i_plane == 3);
-    assert!(picture.img.i_stride[0] == width as i32);
-    assert!(picture.img.i_stride[1] == (width / 2) as i32);
-    assert!(picture.img.i_stride[2] == (width / 2) as i32);
-    assert!(picture.param.is_null());
-    picture.param = &mut picture_param;
-    ///////////////////////////////////////////////////////////////////////////
-    // ???
+    // GO!
     ///////////////////////////////////////////////////////////////////////////
-    let mut p_nal: *mut sys::X264NalT = std::ptr::null_mut();
-    let mut i_nal: i32 = std::mem::zeroed();
+    let encoded = encode_zones(
+        stream.as_frames().iter(),
+        stream.dimensions(),
+        stream.timing(),
+        &shots_report,
+        options,
+    )?;
     ///////////////////////////////////////////////////////////////////////////
-    // ENCODED OUTPUT
+    // DONE
     ///////////////////////////////////////////////////////////////////////////
-    let mut output = Vec::<u8>::new();
+    Ok((encoded, shots_report))
+}
+
+/// Like `opt_video`, without holding the decoded stream in memory. `open`
+/// must yield the same frames (and timestamps, in `time_base` ticks) every
+/// time, the source is read three times: shot detection, sampling and the
+/// final encode. At most one batch of shot samples (`SAMPLE_WINDOW` frames
+/// per shot, one shot per thread) is buffered. 8-bit 4:2:0 only, see
+/// `check_opt_format`. See `opt_shots` for `progress`.
+pub unsafe fn opt_stream<F, I, P>(
+    open: F,
+    time_base: Rational,
+    options: &OptOptions,
+    progress: &P,
+) -> Result<(EncodedVideo, Vec<ShotReport>), String>
+where
+    F: Fn() -> I,
+    I: Iterator<Item = (Yuv420P, i64)>,
+    P: Fn(&ShotReport) + Sync,
+{
     ///////////////////////////////////////////////////////////////////////////
-    // GO!
+    // SHOT DETECTION
     ///////////////////////////////////////////////////////////////////////////
-    let mut frames_meta = Vec::<FrameReport>::new();
-    for (index, source) in stream.as_frames().iter().enumerate() {
-        // BUFFER
-        let (mut y_ptr, mut u_ptr, mut v_ptr) = unsafe {(
-            std::slice::from_raw_parts_mut(picture.img.plane[0], luma_size as usize),
-            std::slice::from_raw_parts_mut(picture.img.plane[1], chroma_size as usize),
-            std::slice::from_raw_parts_mut(picture.img.plane[2], chroma_size as usize),
-        )};
-        y_ptr.copy_from_slice(&source.y());
-        u_ptr.copy_from_slice(&source.u());
-        v_ptr.copy_from_slice(&source.v());
-        // PICTURE SETTINGS
-        let crf = {
-            // let (frame, frame_meta) = opt_frame(index, source.clone());
-            // frames_meta.push(frame_meta.clone());
-            // let path = format!(
-            //     "assets/output/debug/ix={ix}--q={qp}--vmaf={vmaf}--cls={cls}.h264",
-            //     ix=frame_meta.index,
-            //     qp=frame_meta.crf,
-            //     cls=frame_meta.class,
-            //     vmaf=pretty_float(frame_meta.vmaf),
-            // );
-            // std::fs::write(path, frame);
-            // frame_meta.crf
-            frames_report
-                .get(&index)
-                .expect("missing frame report")
-                .crf
-        };
-        apply(&mut picture_param, "crf", &format!("{}", crf));
-        // ENCODE
-        let i_frame_size = sys::x264_encoder_encode(
-            encoder_ctx,
-            &mut p_nal,
-            &mut i_nal,
-            &mut picture,
-            &mut picture_output,
-        );
-        assert!(i_frame_size >= 0);
-        if i_frame_size > 0 {
-            let encoded = std::slice::from_raw_parts(
-                (*p_nal).p_payload,
-                i_frame_size as usize,
-            );
-            output.extend_from_slice(encoded);
+    let mut detector = crate::tool::scene::ShotDetector::new();
+    let mut dimensions: Option<(u32, u32)> = None;
+    let mut pts = Vec::<i64>::new();
+    for (frame, x) in open() {
+        if *dimensions.get_or_insert(frame.dimensions()) != frame.dimensions() {
+            return Err(String::from("frame dimensions changed mid-stream"));
         }
-        // MISC
-        println!("frame: {}", index);
+        pts.push(x);
+        detector.push(frame);
     }
+    let dimensions = dimensions.ok_or(String::from("empty video stream"))?;
+    // CHECK - REJECT BAD OPTIONS BEFORE THE SEARCH
+    new_param(dimensions.0, dimensions.1, PixelFormat::YUV420P, &options.h264)?;
+    let shots = detector.finish();
+    // THE LAST FRAME LASTS AS LONG AS THE ONE BEFORE IT
+    let end_pts = match pts.len() {
+        0 => 0,
+        1 => pts[0] + 1,
+        n => pts[n - 1] + (pts[n - 1] - pts[n - 2]).max(1),
+    };
+    let timing = Timing {time_base, pts, end_pts};
     ///////////////////////////////////////////////////////////////////////////
-    // DEBUG LOG
-    ///////////////////////////////////////////////////////////////////////////
-    // {
-    //     let json = serde_json::to_string_pretty(&frames_meta).expect("to json str");
-    //     std::fs::write("assets/output/test.json", json);
-    // }
-    ///////////////////////////////////////////////////////////////////////////
-    // FLUSH DELAYED FRAMES
+    // SHOT REPORT
     ///////////////////////////////////////////////////////////////////////////
-    while sys::x264_encoder_delayed_frames(encoder_ctx) > 0 {
-        let i_frame_size = sys::x264_encoder_encode(
-            encoder_ctx,
-            &mut p_nal,
-            &mut i_nal,
-            std::ptr::null_mut(),
-            &mut picture_output,
-        );
-        assert!(i_frame_size >= 0);
-        if i_frame_size > 0 {
-            let encoded = std::slice::from_raw_parts(
-                (*p_nal).p_payload,
-                i_frame_size as usize,
-            );
-            output.extend_from_slice(encoded);
+    let opt_batch = |batch: Vec<(Shot, Vec<Yuv420P>)>| -> Result<Vec<ShotReport>, String> {
+        batch
+            .into_par_iter()
+            .map(|(shot, sample)| {
+                let report = opt_sample(sample, time_base, shot, options)?;
+                progress(&report);
+                Ok(report)
+            })
+            .collect()
+    };
+    let mut shots_report = Vec::<ShotReport>::with_capacity(shots.len());
+    let mut batch = Vec::<(Shot, Vec<Yuv420P>)>::new();
+    let mut sample = Vec::<Yuv420P>::new();
+    for (index, (frame, _)) in open().enumerate() {
+        let shot = match shots.get(shots_report.len() + batch.len()) {
+            Some(x) => *x,
+            None => break,
+        };
+        let range = sample_range(&shot);
+        if range.contains(&index) {
+            sample.push(frame);
+        }
+        if index + 1 == range.end {
+            batch.push((shot, std::mem::replace(&mut sample, Vec::new())));
+        }
+        if batch.len() >= rayon::current_num_threads() {
+            shots_report.extend(opt_batch(std::mem::replace(&mut batch, Vec::new()))?);
         }
     }
+    shots_report.extend(opt_batch(batch)?);
+    if shots_report.len() != shots.len() {
+        return Err(String::from("the stream ended early"));
+    }
+    save_debug_report(options, &shots_report);
     ///////////////////////////////////////////////////////////////////////////
-    // CLEANUP
+    // GO!
     ///////////////////////////////////////////////////////////////////////////
-    sys::x264_encoder_close(encoder_ctx);
-    sys::x264_picture_clean(&mut picture);
+    let encoded = encode_zones(
+        open().map(|(frame, _)| frame),
+        dimensions,
+        &timing,
+        &shots_report,
+        options,
+    )?;
     ///////////////////////////////////////////////////////////////////////////
     // DONE
     ///////////////////////////////////////////////////////////////////////////
-    Ok(output)
+    Ok((encoded, shots_report))
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -544,14 +1025,8 @@ pub unsafe fn opt_video(stream: &VideoBuffer) -> Result<Vec<u8>, ()> {
 pub fn run() {
     let source = VideoBuffer::open_video("assets/samples/dump2.h264")
         .expect("decode video file");
-    let output = unsafe {
-        opt_video(&source).expect("opt encode faild")
+    let (output, _) = unsafe {
+        opt_video(&source, &OptOptions::default(), &|_| ()).expect("opt encode faild")
     };
-    std::fs::write("assets/output/test.h264", &output);
+    std::fs::write("assets/output/test.h264", &output.to_annexb());
 }
-
-// pub fn run() {
-//     let source = Yuv420P::open_image("assets/samples/ceiling.jpeg").expect("source image");
-//     let (_, result) = unsafe {opt_frame(source)};
-//     std::fs::write("assets/output/test.h264", &result);
-// }
\ No newline at end of file
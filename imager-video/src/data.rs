@@ -4,6 +4,7 @@
 use std::rc::Rc;
 use std::collections::LinkedList;
 use std::convert::{AsRef, TryFrom};
+use std::str::FromStr;
 use std::path::{PathBuf, Path};
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int};
@@ -11,6 +12,7 @@ use libc::{size_t, c_float, c_void};
 use itertools::Itertools;
 use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer};
 use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
 use webp_dev::sys::webp::{
     self as webp_sys,
     WebPConfig,
@@ -48,7 +50,11 @@ pub fn open_dir_sorted_paths<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
         .collect::<Vec<_>>()
 }
 
+pub use crate::shared::ensure_even_reslution;
+
 unsafe fn convert_to_yuv_using_webp(source: &DynamicImage) -> Yuv420P {
+    // ENSURE IMAGE IS EVEN
+    let source = ensure_even_reslution(source);
     let (width, height) = source.dimensions();
     assert!(width < webp_sys::WEBP_MAX_DIMENSION);
     assert!(height < webp_sys::WEBP_MAX_DIMENSION);
@@ -171,6 +177,231 @@ unsafe fn convert_to_rgba_using_webp(source: &Yuv420P) -> DynamicImage {
 }
 
 
+///////////////////////////////////////////////////////////////////////////////
+// RESOLUTION
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Resolution {
+    pub fn new(width: u32, height: u32) -> Self {
+        Resolution{width, height}
+    }
+}
+
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let ix = input.find("x").ok_or("invalid")?;
+        let (width, height) = input.split_at(ix);
+        let height = height.trim_start_matches("x");
+        let width = u32::from_str(width).map_err(|_| "invalid")?;
+        let height = u32::from_str(height).map_err(|_| "invalid")?;
+        Ok(Resolution {width, height})
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// PIXEL FORMATS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChromaLayout {
+    /// Half width, half height.
+    Yuv420,
+    /// Half width, full height.
+    Yuv422,
+    /// Full resolution.
+    Yuv444,
+}
+
+/// Planar YUV, samples above 8 bits take two (little-endian) bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PixelFormat {
+    pub chroma: ChromaLayout,
+    pub bit_depth: u8,
+}
+
+impl PixelFormat {
+    pub const YUV420P: PixelFormat = PixelFormat {
+        chroma: ChromaLayout::Yuv420,
+        bit_depth: 8,
+    };
+    pub fn new(chroma: ChromaLayout, bit_depth: u8) -> Self {
+        assert!(bit_depth == 8 || bit_depth == 10);
+        PixelFormat {chroma, bit_depth}
+    }
+    pub fn is_high_depth(&self) -> bool {
+        self.bit_depth > 8
+    }
+    pub fn bytes_per_sample(&self) -> usize {
+        if self.is_high_depth() {2} else {1}
+    }
+    /// Width and height of the given plane, in samples.
+    pub fn plane_dimensions(&self, (width, height): (u32, u32), plane: usize) -> (u32, u32) {
+        match (plane, self.chroma) {
+            (0, _) => (width, height),
+            (_, ChromaLayout::Yuv420) => ((width + 1) / 2, (height + 1) / 2),
+            (_, ChromaLayout::Yuv422) => ((width + 1) / 2, height),
+            (_, ChromaLayout::Yuv444) => (width, height),
+        }
+    }
+    /// Size of the given plane, in bytes.
+    pub fn plane_size(&self, dimensions: (u32, u32), plane: usize) -> usize {
+        let (width, height) = self.plane_dimensions(dimensions, plane);
+        (width * height) as usize * self.bytes_per_sample()
+    }
+    pub fn frame_size(&self, dimensions: (u32, u32)) -> usize {
+        (0 .. 3).map(|x| self.plane_size(dimensions, x)).sum()
+    }
+    /// The `fmt` string libvmaf expects.
+    pub fn vmaf_format(&self) -> &'static str {
+        match (self.chroma, self.is_high_depth()) {
+            (ChromaLayout::Yuv420, false) => "yuv420p",
+            (ChromaLayout::Yuv422, false) => "yuv422p",
+            (ChromaLayout::Yuv444, false) => "yuv444p",
+            (ChromaLayout::Yuv420, true) => "yuv420p10le",
+            (ChromaLayout::Yuv422, true) => "yuv422p10le",
+            (ChromaLayout::Yuv444, true) => "yuv444p10le",
+        }
+    }
+    pub fn to_av(&self) -> ffmpeg_dev::sys::AVPixelFormat {
+        use ffmpeg_dev::sys;
+        match (self.chroma, self.is_high_depth()) {
+            (ChromaLayout::Yuv420, false) => sys::AVPixelFormat_AV_PIX_FMT_YUV420P,
+            (ChromaLayout::Yuv422, false) => sys::AVPixelFormat_AV_PIX_FMT_YUV422P,
+            (ChromaLayout::Yuv444, false) => sys::AVPixelFormat_AV_PIX_FMT_YUV444P,
+            (ChromaLayout::Yuv420, true) => sys::AVPixelFormat_AV_PIX_FMT_YUV420P10LE,
+            (ChromaLayout::Yuv422, true) => sys::AVPixelFormat_AV_PIX_FMT_YUV422P10LE,
+            (ChromaLayout::Yuv444, true) => sys::AVPixelFormat_AV_PIX_FMT_YUV444P10LE,
+        }
+    }
+    /// `None` for anything that isn't planar, limited-range 8 or 10-bit YUV.
+    pub fn from_av(format: ffmpeg_dev::sys::AVPixelFormat) -> Option<Self> {
+        use ffmpeg_dev::sys;
+        let formats = [
+            (sys::AVPixelFormat_AV_PIX_FMT_YUV420P, ChromaLayout::Yuv420, 8),
+            (sys::AVPixelFormat_AV_PIX_FMT_YUV422P, ChromaLayout::Yuv422, 8),
+            (sys::AVPixelFormat_AV_PIX_FMT_YUV444P, ChromaLayout::Yuv444, 8),
+            (sys::AVPixelFormat_AV_PIX_FMT_YUV420P10LE, ChromaLayout::Yuv420, 10),
+            (sys::AVPixelFormat_AV_PIX_FMT_YUV422P10LE, ChromaLayout::Yuv422, 10),
+            (sys::AVPixelFormat_AV_PIX_FMT_YUV444P10LE, ChromaLayout::Yuv444, 10),
+        ];
+        formats
+            .iter()
+            .find(|(x, _, _)| *x == format)
+            .map(|(_, chroma, bit_depth)| PixelFormat::new(*chroma, *bit_depth))
+    }
+}
+
+impl std::fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.vmaf_format())
+    }
+}
+
+/// Any supported planar YUV picture, `Yuv420P` is the 8-bit 4:2:0 special
+/// case most of the pipeline works with.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    /// The Y, U and V planes back to back, without padding.
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+    pub fn plane(&self, plane: usize) -> &[u8] {
+        assert!(self.data.len() == self.format.frame_size(self.dimensions()));
+        let start = (0 .. plane)
+            .map(|x| self.format.plane_size(self.dimensions(), x))
+            .sum::<usize>();
+        let end = start + self.format.plane_size(self.dimensions(), plane);
+        &self.data[start .. end]
+    }
+    /// Bytes per row of the given plane.
+    pub fn plane_stride(&self, plane: usize) -> usize {
+        let (width, _) = self.format.plane_dimensions(self.dimensions(), plane);
+        width as usize * self.format.bytes_per_sample()
+    }
+    /// The top-left `width` by `height` region, in the same format.
+    pub fn crop(&self, width: u32, height: u32) -> Frame {
+        assert!(width <= self.width && height <= self.height);
+        let mut data = Vec::with_capacity(self.format.frame_size((width, height)));
+        for plane in 0 .. 3 {
+            let (plane_width, plane_height) = self.format.plane_dimensions((width, height), plane);
+            let row_size = plane_width as usize * self.format.bytes_per_sample();
+            let rows = self.plane(plane)
+                .chunks(self.plane_stride(plane))
+                .take(plane_height as usize);
+            for row in rows {
+                data.extend_from_slice(&row[.. row_size]);
+            }
+        }
+        Frame {
+            width,
+            height,
+            format: self.format,
+            data,
+        }
+    }
+    /// Converts (through libswscale) unless already 8-bit 4:2:0. Odd widths
+    /// or heights are cropped by one pixel, see `ensure_even_reslution`.
+    pub fn to_yuv420p(self) -> Yuv420P {
+        let even = |x: u32| x - (x % 2);
+        let (width, height) = (even(self.width), even(self.height));
+        let source = if self.dimensions() == (width, height) {
+            self
+        } else {
+            self.crop(width, height)
+        };
+        if source.format == PixelFormat::YUV420P {
+            return Yuv420P {
+                width,
+                height,
+                data: source.data,
+            };
+        }
+        let output = crate::format::scale::convert(
+            &source,
+            width,
+            height,
+            PixelFormat::YUV420P,
+            crate::format::scale::ScaleFilter::Bicubic,
+        );
+        Yuv420P {
+            width,
+            height,
+            data: output.data,
+        }
+    }
+}
+
+impl From<Yuv420P> for Frame {
+    fn from(source: Yuv420P) -> Self {
+        Frame {
+            width: source.width,
+            height: source.height,
+            format: PixelFormat::YUV420P,
+            data: source.data,
+        }
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 // PICTURE BUFFERS
 ///////////////////////////////////////////////////////////////////////////////
@@ -187,6 +418,8 @@ impl Yuv420P {
         let source = ::image::open(path).expect("Yuv420P::open_image - load image");
         Yuv420P::from_image(&source)
     }
+    /// Odd widths or heights are cropped by one pixel, see
+    /// `ensure_even_reslution`.
     pub fn from_image(source: &DynamicImage) -> Result<Self, ()> {
         Ok(unsafe{ convert_to_yuv_using_webp(source) })
     }
@@ -256,8 +489,92 @@ impl Yuv420P {
     pub fn dimensions(&self) -> (u32, u32) {
         (self.width, self.height)
     }
+    /// Fit within the given bounds, preserving the aspect ratio.
+    pub fn resize(&self, width: u32, height: u32) -> Yuv420P {
+        use crate::format::scale::{self, ScaleFilter};
+        let (width, height) = scale::fit_within(self.dimensions(), &Resolution::new(width, height));
+        scale::scale(self, width, height, ScaleFilter::Lanczos)
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TIMING
+///////////////////////////////////////////////////////////////////////////////
+
+/// Mirrors `AVRational`, e.g. a time base of `1/1000` means timestamps are
+/// in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rational {
+    pub num: i32,
+    pub den: i32,
+}
+
+impl Rational {
+    pub fn new(num: i32, den: i32) -> Self {
+        Rational {num, den}
+    }
+    pub fn as_f64(&self) -> f64 {
+        (self.num as f64) / (self.den as f64)
+    }
+}
+
+/// The time base used when a source doesn't say, i.e. `25` fps.
+pub const DEFAULT_TIME_BASE: Rational = Rational {num: 1, den: 25};
+
+/// Presentation timestamps of every frame, plus the end of the last frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timing {
+    pub time_base: Rational,
+    pub pts: Vec<i64>,
+    pub end_pts: i64,
+}
+
+impl Timing {
+    /// One tick per frame.
+    pub fn constant(frames: usize, time_base: Rational) -> Self {
+        Timing {
+            time_base,
+            pts: (0 .. frames as i64).collect(),
+            end_pts: frames as i64,
+        }
+    }
+    /// Average frame rate, assuming the first frame starts at zero.
+    pub fn frame_rate(&self) -> Rational {
+        let frames = self.pts.len() as i64;
+        let duration = self.end_pts.max(1);
+        // FRAMES PER (DURATION * TIME-BASE) SECONDS
+        let num = frames * (self.time_base.den as i64);
+        let den = duration * (self.time_base.num as i64);
+        let gcd = {
+            let (mut a, mut b) = (num.abs(), den.abs());
+            while b != 0 {
+                let t = b;
+                b = a % b;
+                a = t;
+            }
+            a.max(1)
+        };
+        Rational::new((num / gcd) as i32, (den / gcd) as i32)
+    }
 }
 
+/// Whether a frame with the given timestamp stays under the frame rate,
+/// i.e. it's the first frame of its `1 / max_fps` second slot. Timestamps
+/// must be given in order.
+pub fn frame_rate_filter(time_base: Rational, max_fps: f64) -> impl FnMut(i64) -> bool {
+    assert!(max_fps > 0.0);
+    let time_base = time_base.as_f64();
+    let mut last_slot: Option<i64> = None;
+    move |pts| {
+        let slot = ((pts as f64) * time_base * max_fps).floor() as i64;
+        let keep = last_slot.map(|last| slot > last).unwrap_or(true);
+        if keep {
+            last_slot = Some(slot);
+        }
+        keep
+    }
+}
 
 ///////////////////////////////////////////////////////////////////////////////
 // VIDEO FRAME BUFFERS
@@ -268,6 +585,7 @@ pub struct VideoBuffer {
     width: u32,
     height: u32,
     frames: Rc<Vec<Yuv420P>>,
+    timing: Rc<Timing>,
     cursor: usize,
 }
 
@@ -277,27 +595,50 @@ impl VideoBuffer {
             width: frame.width,
             height: frame.height,
             frames: Rc::new(vec![frame]),
+            timing: Rc::new(Timing::constant(1, DEFAULT_TIME_BASE)),
             cursor: 0,
         }
     }
-    pub fn load_from_memory(source: &[u8]) -> Result<Self, ()> {
-        let result = unsafe {
-            crate::format::decode::demux_decode(source.to_vec())
-        };
-        assert!(!result.is_empty());
-        let width = result[0].width;
-        let height = result[0].height;
+    pub fn from_frames(frames: Vec<Yuv420P>, timing: Timing) -> Result<Self, ()> {
+        assert!(frames.len() == timing.pts.len());
+        let (width, height) = frames.first().ok_or(())?.dimensions();
+        assert!(frames.iter().all(|x| x.dimensions() == (width, height)));
         Ok(VideoBuffer {
             width,
             height,
-            frames: Rc::new(result),
+            frames: Rc::new(frames),
+            timing: Rc::new(timing),
             cursor: 0,
         })
     }
+    /// Drain a frame reader into memory, keeping the source timestamps.
+    /// Long videos should go through `codec::h264::opt_stream` instead.
+    pub fn from_reader(reader: crate::format::decode::FrameReader) -> Result<Self, ()> {
+        let time_base = reader.time_base();
+        let frame_duration = reader.frame_duration();
+        let (frames, pts): (Vec<_>, Vec<_>) = reader.timed().unzip();
+        let end_pts = pts.last().map(|x| x + frame_duration).unwrap_or(0);
+        let timing = Timing {time_base, pts, end_pts};
+        VideoBuffer::from_frames(frames, timing)
+    }
+    pub fn load_from_memory(source: &[u8]) -> Result<Self, ()> {
+        let reader = crate::format::decode::FrameReader::from_memory(source.to_vec()).map_err(drop)?;
+        VideoBuffer::from_reader(reader)
+    }
+    /// Every GIF frame becomes a video frame, with the GIF frame delays as
+    /// timestamps (in milliseconds).
+    pub fn from_gif(source: &[u8]) -> Result<Self, ()> {
+        let (frames, timing) = crate::format::decode::decode_gif(source).map_err(drop)?;
+        VideoBuffer::from_frames(frames, timing)
+    }
+    pub fn open_gif<P: AsRef<Path>>(path: P) -> Result<Self, ()> {
+        let source = std::fs::read(path).expect("VideoBuffer::open_gif - read source file");
+        VideoBuffer::from_gif(&source)
+    }
     pub fn open_video<P: AsRef<Path>>(path: P) -> Result<Self, ()> {
         assert!(path.as_ref().exists());
-        let source = std::fs::read(path).expect("VideoBuffer::open - read source file");
-        VideoBuffer::load_from_memory(&source)
+        let reader = crate::format::decode::FrameReader::open(path).map_err(drop)?;
+        VideoBuffer::from_reader(reader)
     }
     pub fn open_image_dir<P: AsRef<Path>>(dir_path: P) -> Result<Self, ()> {
         assert!(dir_path.as_ref().exists());
@@ -306,17 +647,8 @@ impl VideoBuffer {
             .map(|path| Yuv420P::open_image(&path).expect("open and decode image"))
             .collect::<Vec<_>>();
         assert!(!frames.is_empty());
-        let (width, height) = {
-            let w = frames[0].width;
-            let h = frames[0].height;
-            (w, h)
-        };
-        Ok(VideoBuffer {
-            width,
-            height,
-            frames: Rc::new(frames),
-            cursor: 0,
-        })
+        let timing = Timing::constant(frames.len(), DEFAULT_TIME_BASE);
+        VideoBuffer::from_frames(frames, timing)
     }
     pub fn width(&self) -> u32 {
         self.width
@@ -330,6 +662,66 @@ impl VideoBuffer {
     pub fn as_frames(&self) -> &[Yuv420P] {
         self.frames.as_ref()
     }
+    pub fn timing(&self) -> &Timing {
+        self.timing.as_ref()
+    }
+    /// Downscale every frame if the resolution exceeds the given size.
+    pub fn max_size(&self, max_size: &Resolution) -> VideoBuffer {
+        if self.width <= max_size.width && self.height <= max_size.height {
+            return self.as_fresh_cursor();
+        }
+        let frames = self.frames
+            .par_iter()
+            .map(|x| x.resize(max_size.width, max_size.height))
+            .collect::<Vec<_>>();
+        VideoBuffer::from_frames(frames, self.timing().clone()).expect("non-empty video")
+    }
+    /// Drop frames so the frame rate doesn't exceed the given rate, the
+    /// remaining frames keep their timestamps.
+    pub fn max_frame_rate(&self, max_fps: f64) -> VideoBuffer {
+        assert!(max_fps > 0.0);
+        if self.timing.frame_rate().as_f64() <= max_fps {
+            return self.as_fresh_cursor();
+        }
+        let mut keep = frame_rate_filter(self.timing.time_base, max_fps);
+        let mut frames = Vec::<Yuv420P>::new();
+        let mut pts = Vec::<i64>::new();
+        for (frame, x) in self.frames.iter().zip(self.timing.pts.iter()) {
+            if keep(*x) {
+                frames.push(frame.clone());
+                pts.push(*x);
+            }
+        }
+        let timing = Timing {
+            time_base: self.timing.time_base,
+            pts,
+            end_pts: self.timing.end_pts,
+        };
+        VideoBuffer::from_frames(frames, timing).expect("non-empty video")
+    }
+    /// Encode (at a fixed CRF) and mux into an MP4 or MKV file.
+    pub fn save_video<P: AsRef<Path>>(
+        &self,
+        path: P,
+        crf: f32,
+        h264_options: &crate::codec::h264::H264Options,
+        options: &crate::format::encode::MuxOptions,
+    ) -> Result<(), String> {
+        unsafe {
+            let encoded = crate::codec::h264::encode_video(self, crf, h264_options)?;
+            crate::format::encode::mux_to_file(&encoded, path, options)
+        }
+    }
+    pub fn save_mp4<P: AsRef<Path>>(&self, path: P, crf: f32) -> Result<(), String> {
+        self.save_video(path, crf, &Default::default(), &Default::default())
+    }
+    pub fn save_mkv<P: AsRef<Path>>(&self, path: P, crf: f32) -> Result<(), String> {
+        let options = crate::format::encode::MuxOptions {
+            container: crate::format::encode::Container::Mkv,
+            ..Default::default()
+        };
+        self.save_video(path, crf, &Default::default(), &options)
+    }
     pub fn into_frames(self) -> Vec<Yuv420P> {
         let refs = Rc::strong_count(&self.frames);
         if refs == 0 {
@@ -354,6 +746,7 @@ impl VideoBuffer {
             width: self.width,
             height: self.height,
             frames: self.frames.clone(),
+            timing: self.timing.clone(),
             cursor: self.cursor,
         }
     }
@@ -42,7 +42,8 @@ use ffmpeg_dev::sys::{
     AV_INPUT_BUFFER_PADDING_SIZE,
     AVPixelFormat_AV_PIX_FMT_YUV420P as AV_PIX_FMT_YUV420P,
 };
-use crate::data::{VideoBuffer, Yuv420P};
+use crate::data::{VideoBuffer, Yuv420P, Frame, PixelFormat, ChromaLayout, Rational, Timing};
+use crate::format::scale;
 
 
 fn c_str(s: &str) -> CString {
@@ -174,7 +175,8 @@ struct Decoder {
 
     demux_ops: *mut sys::AVDictionary,
 
-    decoded_video: LinkedList<RawYuv420p>,
+    /// Decoded frames, with their presentation timestamps.
+    decoded_video: LinkedList<(Frame, i64)>,
     decoded_audio: LinkedList<u8>,
 
     video_dst_data: [*mut u8; 4],
@@ -268,6 +270,52 @@ impl Decoder {
 
 
 
+/// The nearest supported format, for sources that decode to something else
+/// (e.g. packed or full-range formats).
+unsafe fn fallback_format(format: sys::AVPixelFormat) -> PixelFormat {
+    let desc = sys::av_pix_fmt_desc_get(format);
+    if desc.is_null() {
+        return PixelFormat::YUV420P;
+    }
+    let chroma = match ((*desc).log2_chroma_w, (*desc).log2_chroma_h) {
+        (0, 0) => ChromaLayout::Yuv444,
+        (1, 0) => ChromaLayout::Yuv422,
+        _ => ChromaLayout::Yuv420,
+    };
+    let bit_depth = if (*desc).comp[0].depth > 8 {10} else {8};
+    PixelFormat::new(chroma, bit_depth)
+}
+
+/// Copy a decoded picture into a tightly packed buffer, converting it if
+/// the pixel format isn't supported as is.
+unsafe fn copy_av_frame(frame: *const AVFrame) -> Frame {
+    let (width, height) = ((*frame).width, (*frame).height);
+    match PixelFormat::from_av((*frame).format) {
+        Some(format) => {
+            let size = format.frame_size((width as u32, height as u32));
+            let mut data = vec![0u8; size];
+            let status = sys::av_image_copy_to_buffer(
+                data.as_mut_ptr(),
+                size as i32,
+                (*frame).data.as_ptr() as *const *const u8,
+                (*frame).linesize.as_ptr(),
+                (*frame).format,
+                width,
+                height,
+                1,
+            );
+            assert!(status == size as i32);
+            Frame {
+                width: width as u32,
+                height: height as u32,
+                format,
+                data,
+            }
+        }
+        None => scale::convert_av_frame(frame, fallback_format((*frame).format)),
+    }
+}
+
 unsafe fn decode_packet(
     got_frame: &mut i32,
     cached: i32,
@@ -316,28 +364,9 @@ unsafe fn decode_packet(
                 decoder.height,
             );
 
-            // WRITE TO RAWVIDEO FILE
-            // fwrite(video_dst_data[0], 1, video_dst_bufsize, video_dst_file);
-            {
-                let mut output_picture: RawYuv420p = RawYuv420p::new(
-                    (*decoder.frame).width as u32,
-                    (*decoder.frame).height as u32,
-                );
-    
-                // COPY DECODED FRAME TO DESTINATION BUFFER;
-                // THIS IS REQUIRED SINCE RAWVIDEO EXPECTS NON ALIGNED DATA
-                // sys::av_image_copy(
-                //     output_picture.data.as_mut_ptr(),
-                //     output_picture.linesize.as_mut_ptr(),
-                //     decoder.video_dst_data.as_mut_ptr() as *mut *const u8,
-                //     decoder.video_dst_linesize.as_mut_ptr(),
-                //     AV_PIX_FMT_YUV420P,
-                //     decoder.width,
-                //     decoder.height,
-                // );
-                output_picture.fill_from_frame(decoder.frame);
-                decoder.decoded_video.push_back(output_picture);
-            }
+            // KEEP THE DECODED FRAME
+            let pts = (*decoder.frame).best_effort_timestamp;
+            decoder.decoded_video.push_back((copy_av_frame(decoder.frame), pts));
         }
     } else if (decoder.pkt.stream_index == decoder.audio_stream_idx) {
         // DECODE AUDIO FRAME
@@ -526,242 +555,395 @@ unsafe fn get_format_from_sample_fmt(
 }
 
 
-pub unsafe fn demux_decode(source: Vec<u8>) -> Vec<Yuv420P> {
-    // DEBUG
-    let suppress_log = true;
-    sys::av_log_set_level(16);
-    // SETUP
-    let mut got_frame: i32 = std::mem::zeroed();
-    let mut ret = 0;
-    let mut decoder = Decoder::new();
-
-    // CUSTOM AV-IO-CONTEXT HELPERS
-    struct CallbackContext {
-        buffer: Cursor<Vec<u8>>,
-    }
-    
-    unsafe extern "C" fn read_packet(
-        ctx: *mut c_void,
-        buf: *mut u8,
-        buf_size: i32,
-    ) -> i32 {
-        // INIT
-        let ctx = (ctx as *mut CallbackContext)
-            .as_mut()
-            .expect("not null");
-        assert!(buf_size >= 0);
-        let buf_size = buf_size as usize;
-        // CHECK
-        let mut chunk = {
-            let xs = ctx.buffer
-                .get_ref()
-                .split_at(ctx.buffer.position() as usize).1
-                .chunks(buf_size)
-                .nth(0);
-            if xs.is_none() {
-                return ffmpeg_dev::extra::defs::averror_eof();
-            }
-            xs.expect("not empty").to_vec()
-        };
-        ctx.buffer.seek(SeekFrom::Current(chunk.len() as i64));
-        assert!(chunk.len() <= buf_size);
-        let chunk_size = chunk.len();
-        for (ix, x) in chunk.into_iter().enumerate() {
-            (*buf.add(ix)) = x;
+///////////////////////////////////////////////////////////////////////////////
+// STREAMING DECODER
+///////////////////////////////////////////////////////////////////////////////
+
+/// Anything the custom AVIO context can read from.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+struct CallbackContext {
+    reader: Box<dyn ReadSeek>,
+}
+
+unsafe extern "C" fn read_packet(
+    ctx: *mut c_void,
+    buf: *mut u8,
+    buf_size: i32,
+) -> i32 {
+    // INIT
+    let ctx = (ctx as *mut CallbackContext)
+        .as_mut()
+        .expect("not null");
+    assert!(buf_size >= 0);
+    let buf = std::slice::from_raw_parts_mut(buf, buf_size as usize);
+    // GO
+    match ctx.reader.read(buf) {
+        Ok(0) => ffmpeg_dev::extra::defs::averror_eof(),
+        Ok(chunk_size) => chunk_size as i32,
+        Err(_) => ffmpeg_dev::extra::defs::averror_eof(),
+    }
+}
+
+unsafe extern "C" fn seek_packet(
+    opaque: *mut ::std::os::raw::c_void,
+    offset: i64,
+    whence: ::std::os::raw::c_int,
+) -> i64 {
+    // INIT
+    let ctx = (opaque as *mut CallbackContext)
+        .as_mut()
+        .expect("not null");
+    // CHECK
+    assert!(whence >= 0);
+    // MODES
+    const SEEK_SET: i32 = 0;
+    const SEEK_CUR: i32 = 1;
+    const SEEK_END: i32 = 2;
+    const AVSEEK_SIZE: i32 = sys::AVSEEK_SIZE as i32;
+    // GO
+    let seek_from = match whence {
+        SEEK_SET => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        AVSEEK_SIZE => {
+            let position = ctx.reader.seek(SeekFrom::Current(0));
+            let size = ctx.reader.seek(SeekFrom::End(0));
+            return match (position, size) {
+                (Ok(position), Ok(size)) => {
+                    ctx.reader.seek(SeekFrom::Start(position)).expect("seek to position");
+                    size as i64
+                }
+                _ => -1
+            };
         }
-        // DONE
-        chunk_size as i32
-    }
-
-    unsafe extern "C" fn seek_packet(
-        opaque: *mut ::std::os::raw::c_void,
-        offset: i64,
-        whence: ::std::os::raw::c_int,
-    ) -> i64 {
-        use std::io::SeekFrom;
-        // INIT
-        let ctx = (opaque as *mut CallbackContext)
-            .as_mut()
-            .expect("not null");
-        // CHECK
-        assert!(whence >= 0);
-        // MODES
-        const SEEK_SET: i32 = 0;
-        const SEEK_CUR: i32 = 1;
-        const SEEK_END: i32 = 2;
-        const AVSEEK_SIZE: i32 = sys::AVSEEK_SIZE as i32;
-        // GO
-        let seek_from = match whence {
-            SEEK_SET => SeekFrom::Start(offset as u64),
-            SEEK_CUR => SeekFrom::Current(offset),
-            SEEK_END => SeekFrom::End(offset),
-            AVSEEK_SIZE => {
-                return ctx.buffer.get_ref().len() as i64;
+        // UNSUPPORTED (E.G. AVSEEK_FORCE) - AVERROR(EINVAL)
+        _ => return -(libc::EINVAL as i64)
+    };
+    match ctx.reader.seek(seek_from) {
+        Ok(position) => position as i64,
+        Err(_) => -1,
+    }
+}
+
+/// Decodes one frame at a time, so memory stays bounded regardless of the
+/// length of the video.
+pub struct FrameReader {
+    decoder: Decoder,
+    avio_ctx: *mut sys::AVIOContext,
+    // BOXED, SO THE ADDRESS GIVEN TO THE AVIO CONTEXT STAYS VALID
+    io: Box<CallbackContext>,
+    eof: bool,
+    flushed: bool,
+    first_pts: Option<i64>,
+    last_pts: Option<i64>,
+}
+
+impl Drop for FrameReader {
+    fn drop(&mut self) {
+        unsafe {
+            // THE FORMAT CONTEXT MUST BE CLOSED BEFORE ITS (CUSTOM) IO
+            if !self.decoder.fmt_ctx.is_null() {
+                sys::avformat_close_input(&mut self.decoder.fmt_ctx);
+                self.decoder.fmt_ctx = std::ptr::null_mut();
+            }
+            if !self.avio_ctx.is_null() {
+                sys::av_freep(&mut (*self.avio_ctx).buffer as *mut *mut u8 as *mut c_void);
+                sys::avio_context_free(&mut self.avio_ctx);
             }
-            _ => panic!("what to do here?")
         };
-        ctx.buffer.seek(seek_from).expect("seek to position") as i64
     }
+}
 
-    // INIT CUSTOM AV-IO-CONTEXT
-    let avio_ctx_buffer_size = 4096;
-    // let avio_ctx_buffer_size = source.len();
-    let mut avio_ctx_buffer: *mut u8 = sys::av_malloc(avio_ctx_buffer_size) as *mut u8;
-    let mut bd: CallbackContext = CallbackContext{
-        buffer: Cursor::new(source),
-    };
-    let mut avio_ctx: *mut sys::AVIOContext = sys::avio_alloc_context(
-        avio_ctx_buffer,
-        avio_ctx_buffer_size as i32,
-        0,
-        (&mut bd as *mut CallbackContext) as *mut c_void,
-        Some(read_packet),
-        None,
-        Some(seek_packet),
-    );
-    assert!(!avio_ctx.is_null());
-        
-    // OPEN INPUT FILE, AND ALLOCATE FORMAT CONTEXT
-    assert!(!decoder.fmt_ctx.is_null());
-    assert!(!decoder.fmt_ctx.is_null());
-    assert!((*decoder.fmt_ctx).pb.is_null());
-    (*decoder.fmt_ctx).pb = avio_ctx;
-    (*decoder.fmt_ctx).flags = sys::AVFMT_FLAG_CUSTOM_IO as i32;
-    (*decoder.fmt_ctx).probesize = 1200000;
-    {
-        let status = sys::avformat_open_input(
-            &mut decoder.fmt_ctx,
-            std::ptr::null_mut(),
-            std::ptr::null_mut(),
-            &mut decoder.demux_ops,
-        );
-        if status < 0 {
-            panic!("Could not open source file: {}", status);
+impl FrameReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let file = std::fs::File::open(path).map_err(|e| format!("{}", e))?;
+        FrameReader::new(std::io::BufReader::new(file))
+    }
+    pub fn from_memory(source: Vec<u8>) -> Result<Self, String> {
+        FrameReader::new(Cursor::new(source))
+    }
+    pub fn new<R: Read + Seek + 'static>(reader: R) -> Result<Self, String> {
+        unsafe {
+            FrameReader::init(Box::new(CallbackContext {
+                reader: Box::new(reader),
+            }))
         }
     }
-
-    // RETRIEVE STREAM INFORMATION
-    if (sys::avformat_find_stream_info(decoder.fmt_ctx, std::ptr::null_mut()) < 0) {
-        panic!("Could not find stream information");
-    }
-
-    if (open_codec_context(
-        &mut decoder.video_stream_idx,
-        &mut decoder.video_dec_ctx,
-        decoder.fmt_ctx,
-        AVMEDIA_TYPE_VIDEO,
-    ) >= 0) {
-        decoder.video_stream = (*(*decoder.fmt_ctx).streams).add(decoder.video_stream_idx as usize);
-
-        // ALLOCATE IMAGE WHERE THE DECODED IMAGE WILL BE PUT
-        decoder.width = (*decoder.video_dec_ctx).width;
-        decoder.height = (*decoder.video_dec_ctx).height;
-        decoder.pix_fmt = (*decoder.video_dec_ctx).pix_fmt;
-        ret = sys::av_image_alloc(
-            decoder.video_dst_data.as_mut_ptr(),
-            decoder.video_dst_linesize.as_mut_ptr(),
-            decoder.width,
-            decoder.height,
-            decoder.pix_fmt,
-            1,
+    unsafe fn init(mut io: Box<CallbackContext>) -> Result<Self, String> {
+        // DEBUG
+        let suppress_log = true;
+        sys::av_log_set_level(16);
+        // SETUP
+        let mut decoder = Decoder::new();
+
+        // INIT CUSTOM AV-IO-CONTEXT
+        let avio_ctx_buffer_size = 4096;
+        let avio_ctx_buffer: *mut u8 = sys::av_malloc(avio_ctx_buffer_size) as *mut u8;
+        let avio_ctx: *mut sys::AVIOContext = sys::avio_alloc_context(
+            avio_ctx_buffer,
+            avio_ctx_buffer_size as i32,
+            0,
+            (io.as_mut() as *mut CallbackContext) as *mut c_void,
+            Some(read_packet),
+            None,
+            Some(seek_packet),
         );
-        if (ret < 0) {
-            panic!("Could not allocate raw video buffer");
+        assert!(!avio_ctx.is_null());
+
+        // OPEN INPUT FILE, AND ALLOCATE FORMAT CONTEXT
+        assert!(!decoder.fmt_ctx.is_null());
+        assert!((*decoder.fmt_ctx).pb.is_null());
+        (*decoder.fmt_ctx).pb = avio_ctx;
+        (*decoder.fmt_ctx).flags = sys::AVFMT_FLAG_CUSTOM_IO as i32;
+        (*decoder.fmt_ctx).probesize = 1200000;
+        let mut reader = FrameReader {
+            decoder,
+            avio_ctx,
+            io,
+            eof: false,
+            flushed: false,
+            first_pts: None,
+            last_pts: None,
+        };
+        let decoder = &mut reader.decoder;
+        {
+            let status = sys::avformat_open_input(
+                &mut decoder.fmt_ctx,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &mut decoder.demux_ops,
+            );
+            if status < 0 {
+                return Err(format!("Could not open source file: {}", status));
+            }
+        }
+
+        // RETRIEVE STREAM INFORMATION
+        if (sys::avformat_find_stream_info(decoder.fmt_ctx, std::ptr::null_mut()) < 0) {
+            return Err(String::from("Could not find stream information"));
         }
-        decoder.video_dst_bufsize = ret;
-    }
 
-    let get_audio_stream = false;
-    if get_audio_stream {
         if (open_codec_context(
-            &mut decoder.audio_stream_idx,
-            &mut decoder.audio_dec_ctx,
+            &mut decoder.video_stream_idx,
+            &mut decoder.video_dec_ctx,
             decoder.fmt_ctx,
-            AVMEDIA_TYPE_AUDIO
+            AVMEDIA_TYPE_VIDEO,
         ) >= 0) {
-            decoder.audio_stream = (*(*decoder.fmt_ctx).streams).add(decoder.audio_stream_idx as usize);
+            decoder.video_stream = *(*decoder.fmt_ctx).streams.offset(decoder.video_stream_idx as isize);
+
+            // ALLOCATE IMAGE WHERE THE DECODED IMAGE WILL BE PUT
+            decoder.width = (*decoder.video_dec_ctx).width;
+            decoder.height = (*decoder.video_dec_ctx).height;
+            decoder.pix_fmt = (*decoder.video_dec_ctx).pix_fmt;
+            let ret = sys::av_image_alloc(
+                decoder.video_dst_data.as_mut_ptr(),
+                decoder.video_dst_linesize.as_mut_ptr(),
+                decoder.width,
+                decoder.height,
+                decoder.pix_fmt,
+                1,
+            );
+            if (ret < 0) {
+                return Err(String::from("Could not allocate raw video buffer"));
+            }
+            decoder.video_dst_bufsize = ret;
         }
-    }
 
-    // DUMP INPUT INFORMATION TO STDERR
-    if !suppress_log {
-        sys::av_dump_format(
-            decoder.fmt_ctx,
-            0,
-            std::ptr::null(),
-            0,
-        );
-    }
+        // DUMP INPUT INFORMATION TO STDERR
+        if !suppress_log {
+            sys::av_dump_format(
+                decoder.fmt_ctx,
+                0,
+                std::ptr::null(),
+                0,
+            );
+        }
 
-    if (decoder.audio_stream.is_null() && decoder.video_stream.is_null()) {
-        panic!("Could not find audio or video stream in the input, aborting");
-    }
+        if decoder.video_stream.is_null() {
+            return Err(String::from("Could not find video stream in the input, aborting"));
+        }
 
-    decoder.frame = sys::av_frame_alloc();
-    if (decoder.frame.is_null()) {
-        panic!("Could not allocate frame");
-    }
+        decoder.frame = sys::av_frame_alloc();
+        if (decoder.frame.is_null()) {
+            return Err(String::from("Could not allocate frame"));
+        }
 
-    // INITIALIZE PACKET - SET DATA TO NULL - LET THE DEMUXER FILL IT
-    sys::av_init_packet(&mut decoder.pkt);
-    decoder.pkt.data = std::ptr::null_mut();
-    decoder.pkt.size = 0;
+        // INITIALIZE PACKET - SET DATA TO NULL - LET THE DEMUXER FILL IT
+        sys::av_init_packet(&mut decoder.pkt);
+        decoder.pkt.data = std::ptr::null_mut();
+        decoder.pkt.size = 0;
 
-    if (!decoder.video_stream.is_null()) {
-        if !suppress_log {
-            println!("Demuxing video from file");
+        // DONE
+        Ok(reader)
+    }
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.decoder.width as u32, self.decoder.height as u32)
+    }
+    /// Pixel format of the frames from `next_frame`.
+    pub fn format(&self) -> PixelFormat {
+        let pix_fmt = self.decoder.pix_fmt;
+        PixelFormat::from_av(pix_fmt).unwrap_or_else(|| unsafe {fallback_format(pix_fmt)})
+    }
+    /// The stream's own time base, see `next_timed_frame`.
+    pub fn time_base(&self) -> Rational {
+        let time_base = unsafe {
+            (*self.decoder.video_stream).time_base
+        };
+        if time_base.num > 0 && time_base.den > 0 {
+            Rational::new(time_base.num, time_base.den)
+        } else {
+            crate::data::DEFAULT_TIME_BASE
         }
     }
-    if (!decoder.audio_stream.is_null()) {
-        if !suppress_log {
-            println!("Demuxing audio from file");
+    /// Average frame duration in `time_base` ticks, for frames without a
+    /// timestamp and the duration of the last frame.
+    pub fn frame_duration(&self) -> i64 {
+        let frame_rate = unsafe {
+            (*self.decoder.video_stream).avg_frame_rate
+        };
+        let time_base = self.time_base();
+        if frame_rate.num <= 0 || frame_rate.den <= 0 {
+            return 1;
         }
+        let num = (time_base.den as i64) * (frame_rate.den as i64);
+        let den = (time_base.num as i64) * (frame_rate.num as i64);
+        (num / den).max(1)
     }
-
-    // READ FRAMES FROM THE FILE
-    while sys::av_read_frame(decoder.fmt_ctx, &mut decoder.pkt) >= 0 {
+    /// Demux and decode the next packet. Returns `false` at the end of the
+    /// stream.
+    unsafe fn read_packet(&mut self) -> bool {
+        let decoder = &mut self.decoder;
+        let mut got_frame: i32 = 0;
+        if sys::av_read_frame(decoder.fmt_ctx, &mut decoder.pkt) < 0 {
+            return false;
+        }
         let mut orig_pkt: AVPacket = decoder.pkt.clone();
-        {
-            ret = decode_packet(&mut got_frame, 0, &mut decoder);
+        loop {
+            let ret = decode_packet(&mut got_frame, 0, decoder);
             if (ret < 0) {
                 break;
             }
             decoder.pkt.data = decoder.pkt.data.add(ret as usize);
             decoder.pkt.size = decoder.pkt.size - ret;
-        }
-        while decoder.pkt.size > 0 {
-            ret = decode_packet(&mut got_frame, 0, &mut decoder);
-            if (ret < 0) {
+            if decoder.pkt.size <= 0 {
                 break;
             }
-            decoder.pkt.data = decoder.pkt.data.add(ret as usize);
-            decoder.pkt.size = decoder.pkt.size - ret;
         }
         sys::av_packet_unref(&mut orig_pkt);
+        true
     }
+    /// Drain the frames still cached in the decoder.
+    unsafe fn flush(&mut self) {
+        let decoder = &mut self.decoder;
+        let mut got_frame: i32 = 0;
+        decoder.pkt.data = std::ptr::null_mut();
+        decoder.pkt.size = 0;
+        decode_packet(&mut got_frame, 1, decoder);
+        while got_frame > 0 {
+            decode_packet(&mut got_frame, 1, decoder);
+        }
+    }
+}
 
-    // FLUSH CACHED FRAMES
-    decoder.pkt.data = std::ptr::null_mut();
-    decoder.pkt.size = 0;
-    decode_packet(&mut got_frame, 1, &mut decoder);
-    while got_frame > 0 {
-        decode_packet(&mut got_frame, 1, &mut decoder);
+impl FrameReader {
+    /// The next frame, in the source's own pixel format.
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        self.next_timed_frame().map(|(frame, _)| frame)
+    }
+    /// The next frame and its presentation timestamp (in `time_base`
+    /// ticks), relative to the first frame. Variable frame rate sources
+    /// keep their timestamps, missing ones are extrapolated.
+    pub fn next_timed_frame(&mut self) -> Option<(Frame, i64)> {
+        loop {
+            if let Some((frame, pts)) = self.decoder.decoded_video.pop_front() {
+                let missing = pts == crate::format::AV_NOPTS_VALUE;
+                let pts = match self.last_pts {
+                    Some(last) if missing || pts <= last => last + self.frame_duration(),
+                    None if missing => 0,
+                    _ => pts,
+                };
+                self.last_pts = Some(pts);
+                let first_pts = *self.first_pts.get_or_insert(pts);
+                return Some((frame, pts - first_pts));
+            }
+            if self.flushed {
+                return None;
+            }
+            if !self.eof {
+                self.eof = unsafe {!self.read_packet()};
+            } else {
+                unsafe {self.flush()};
+                self.flushed = true;
+            }
+        }
+    }
+    /// Iterate in the source's own pixel format, e.g. 10-bit 4:2:2.
+    pub fn frames(mut self) -> impl Iterator<Item = Frame> {
+        std::iter::from_fn(move || self.next_frame())
+    }
+    /// Like the `Iterator` impl, with the timestamps of `next_timed_frame`.
+    pub fn timed(mut self) -> impl Iterator<Item = (Yuv420P, i64)> {
+        std::iter::from_fn(move || {
+            self.next_timed_frame().map(|(frame, pts)| (frame.to_yuv420p(), pts))
+        })
     }
+}
 
-    if !suppress_log {
-        println!("Demuxing succeeded");
+/// Frames are converted to 8-bit 4:2:0, see `FrameReader::frames` for the
+/// source's own format.
+impl Iterator for FrameReader {
+    type Item = Yuv420P;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().map(|x| x.to_yuv420p())
     }
+}
 
-    // RETURN VALUES
-    let decoded_frames = decoder.decoded_video
-        .iter()
-        .map(|x| x.to_higher())
-        .collect::<Vec<_>>();
+/// Decode every frame into memory, prefer `FrameReader` for long videos.
+pub unsafe fn demux_decode(source: Vec<u8>) -> Vec<Yuv420P> {
+    FrameReader::from_memory(source)
+        .expect("open video source")
+        .collect()
+}
 
-    // CLEANUP
-    std::mem::drop(decoder);
-    sys::avio_context_free(&mut avio_ctx);
 
-    // DONE
-    decoded_frames
+///////////////////////////////////////////////////////////////////////////////
+// GIF
+///////////////////////////////////////////////////////////////////////////////
+
+/// Decode every (composited) GIF frame, with timestamps in milliseconds.
+pub fn decode_gif(source: &[u8]) -> Result<(Vec<Yuv420P>, Timing), String> {
+    use image::{AnimationDecoder, DynamicImage};
+    let decoder = ::image::gif::Decoder::new(Cursor::new(source))
+        .map_err(|e| format!("gif decoder: {}", e))?;
+    let frames = decoder
+        .into_frames()
+        .collect_frames()
+        .map_err(|e| format!("gif frames: {}", e))?;
+    if frames.is_empty() {
+        return Err(String::from("gif without frames"));
+    }
+    let mut pts = Vec::<i64>::with_capacity(frames.len());
+    let mut end_pts: i64 = 0;
+    for frame in frames.iter() {
+        let delay = crate::shared::gif_delay(frame.delay().to_integer() as u32) as i64;
+        pts.push(end_pts);
+        end_pts = end_pts + delay;
+    }
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let frame = DynamicImage::ImageRgba8(frame.into_buffer());
+            // Odd canvas sizes lose their last row/column here.
+            Yuv420P::from_image(&frame).map_err(|_| String::from("gif frame to yuv420p"))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let timing = Timing {
+        time_base: Rational::new(1, 1000),
+        pts,
+        end_pts,
+    };
+    Ok((frames, timing))
 }
@@ -0,0 +1,421 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use std::collections::HashMap;
+use std::convert::AsRef;
+use std::path::{PathBuf, Path};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::str::FromStr;
+use libc::{size_t, c_float, c_void};
+use serde::{Serialize, Deserialize};
+use ffmpeg_dev::sys::{
+    self,
+    AVPacket,
+    AVRational,
+    AVStream,
+    AVDictionary,
+    AVFormatContext,
+    AVMediaType_AVMEDIA_TYPE_VIDEO as AVMEDIA_TYPE_VIDEO,
+    AVMediaType_AVMEDIA_TYPE_AUDIO as AVMEDIA_TYPE_AUDIO,
+    AVFMT_NOFILE,
+    AVIO_FLAG_WRITE,
+    AVCodecID_AV_CODEC_ID_H264 as AV_CODEC_ID_H264,
+    AV_INPUT_BUFFER_PADDING_SIZE,
+    AV_PKT_FLAG_KEY,
+    AVPixelFormat_AV_PIX_FMT_YUV420P as AV_PIX_FMT_YUV420P,
+};
+
+use crate::data::{VideoBuffer, Rational};
+use crate::codec::h264::{self, EncodedVideo};
+
+
+fn c_str(s: &str) -> CString {
+    CString::new(s).expect("str to c str")
+}
+
+fn to_av_rational(x: Rational) -> AVRational {
+    AVRational {num: x.num, den: x.den}
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Packets are in decoding order, so the duration of a packet is the
+/// distance to the next presentation timestamp.
+fn packet_durations(video: &EncodedVideo) -> HashMap<i64, i64> {
+    let mut pts = video.packets
+        .iter()
+        .map(|x| x.pts)
+        .collect::<Vec<_>>();
+    pts.sort();
+    let mut durations = HashMap::new();
+    for (ix, x) in pts.iter().enumerate() {
+        let next = pts.get(ix + 1).cloned().unwrap_or(video.timing.end_pts);
+        durations.insert(*x, (next - *x).max(0));
+    }
+    durations
+}
+
+/// The timestamp audio packets are interleaved by, demuxers may leave the
+/// `dts` unset.
+fn interleave_ts(pkt: &AVPacket) -> i64 {
+    if pkt.dts != super::AV_NOPTS_VALUE {pkt.dts} else {pkt.pts}
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// OPTIONS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Container {
+    Mp4,
+    Mkv,
+}
+
+impl Container {
+    fn format_name(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "matroska",
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+        }
+    }
+}
+
+impl FromStr for Container {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mp4" => Ok(Container::Mp4),
+            "mkv" => Ok(Container::Mkv),
+            "matroska" => Ok(Container::Mkv),
+            _ => Err(format!("Unknown or unsupported container {}", s)),
+        }
+    }
+}
+
+impl Default for Container {
+    fn default() -> Self {
+        Container::Mp4
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MuxOptions {
+    pub container: Container,
+    /// MP4 only, moves the `moov` atom to the front so playback can start
+    /// before the whole file is downloaded.
+    pub faststart: bool,
+    /// Copy the (best) audio stream of this file into the output, as is.
+    pub audio_source: Option<PathBuf>,
+}
+
+impl Default for MuxOptions {
+    fn default() -> Self {
+        MuxOptions {
+            container: Container::Mp4,
+            faststart: true,
+            audio_source: None,
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// AUDIO PASSTHROUGH
+///////////////////////////////////////////////////////////////////////////////
+
+struct AudioSource {
+    fmt_ctx: *mut AVFormatContext,
+    stream_index: i32,
+    input_time_base: AVRational,
+    output_stream: *mut AVStream,
+}
+
+impl Drop for AudioSource {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.fmt_ctx.is_null() {
+                sys::avformat_close_input(&mut self.fmt_ctx);
+                self.fmt_ctx = std::ptr::null_mut();
+            }
+        };
+    }
+}
+
+impl AudioSource {
+    /// Returns `None` if the file has no audio stream.
+    unsafe fn open(path: &Path, output_ctx: *mut AVFormatContext) -> Result<Option<Self>, String> {
+        let path = path
+            .to_str()
+            .ok_or(String::from("non UTF-8 audio source path"))?;
+        let path = c_str(path);
+        let mut fmt_ctx: *mut AVFormatContext = std::ptr::null_mut();
+        let status = sys::avformat_open_input(
+            &mut fmt_ctx,
+            path.as_ptr(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        );
+        if status < 0 {
+            return Err(format!("could not open audio source: {}", status));
+        }
+        let mut audio = AudioSource {
+            fmt_ctx,
+            stream_index: -1,
+            input_time_base: AVRational {num: 0, den: 1},
+            output_stream: std::ptr::null_mut(),
+        };
+        if sys::avformat_find_stream_info(fmt_ctx, std::ptr::null_mut()) < 0 {
+            return Err(String::from("could not find audio source stream information"));
+        }
+        let stream_index = sys::av_find_best_stream(
+            fmt_ctx,
+            AVMEDIA_TYPE_AUDIO,
+            -1,
+            -1,
+            std::ptr::null_mut(),
+            0,
+        );
+        if stream_index < 0 {
+            return Ok(None);
+        }
+        let input_stream = *(*fmt_ctx).streams.offset(stream_index as isize);
+        let output_stream = sys::avformat_new_stream(output_ctx, std::ptr::null());
+        assert!(!output_stream.is_null());
+        if sys::avcodec_parameters_copy((*output_stream).codecpar, (*input_stream).codecpar) < 0 {
+            return Err(String::from("could not copy audio codec parameters"));
+        }
+        // THE SOURCE CONTAINER'S TAG MAY BE INVALID FOR THE OUTPUT CONTAINER
+        (*(*output_stream).codecpar).codec_tag = 0;
+        (*output_stream).time_base = (*input_stream).time_base;
+        audio.stream_index = stream_index;
+        audio.input_time_base = (*input_stream).time_base;
+        audio.output_stream = output_stream;
+        Ok(Some(audio))
+    }
+    /// The next audio packet, with timestamps in the output stream's time base.
+    unsafe fn next_packet(&mut self) -> Option<AVPacket> {
+        let mut pkt: AVPacket = std::mem::zeroed();
+        sys::av_init_packet(&mut pkt);
+        while sys::av_read_frame(self.fmt_ctx, &mut pkt) >= 0 {
+            if pkt.stream_index == self.stream_index {
+                sys::av_packet_rescale_ts(
+                    &mut pkt,
+                    self.input_time_base,
+                    (*self.output_stream).time_base,
+                );
+                pkt.stream_index = (*self.output_stream).index;
+                pkt.pos = -1;
+                return Some(pkt);
+            }
+            sys::av_packet_unref(&mut pkt);
+        }
+        None
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// MUXER
+///////////////////////////////////////////////////////////////////////////////
+
+/// Write the encoded H.264 stream into an MP4 or MKV file.
+pub unsafe fn mux_to_file<P: AsRef<Path>>(
+    video: &EncodedVideo,
+    path: P,
+    options: &MuxOptions,
+) -> Result<(), String> {
+    let path = path
+        .as_ref()
+        .to_str()
+        .ok_or(String::from("non UTF-8 output path"))?;
+    let path = c_str(path);
+    let format_name = c_str(options.container.format_name());
+    let source_time_base = to_av_rational(video.timing.time_base);
+    ///////////////////////////////////////////////////////////////////////////
+    // OUTPUT CONTEXT
+    ///////////////////////////////////////////////////////////////////////////
+    let mut fmt_ctx: *mut AVFormatContext = std::ptr::null_mut();
+    sys::avformat_alloc_output_context2(
+        &mut fmt_ctx,
+        std::ptr::null_mut(),
+        format_name.as_ptr(),
+        path.as_ptr(),
+    );
+    if fmt_ctx.is_null() {
+        return Err(String::from("could not allocate output context"));
+    }
+    ///////////////////////////////////////////////////////////////////////////
+    // VIDEO STREAM
+    ///////////////////////////////////////////////////////////////////////////
+    let stream = sys::avformat_new_stream(fmt_ctx, std::ptr::null());
+    assert!(!stream.is_null());
+    (*stream).time_base = source_time_base;
+    (*stream).avg_frame_rate = to_av_rational(video.timing.frame_rate());
+    let codecpar = (*stream).codecpar;
+    (*codecpar).codec_type = AVMEDIA_TYPE_VIDEO;
+    (*codecpar).codec_id = AV_CODEC_ID_H264;
+    (*codecpar).format = video.format.to_av();
+    (*codecpar).width = video.width as i32;
+    (*codecpar).height = video.height as i32;
+    // SPS & PPS - BOTH MUXERS CONVERT ANNEX-B EXTRADATA TO `avcC`
+    {
+        let size = video.headers.len();
+        let extradata = sys::av_mallocz(size + AV_INPUT_BUFFER_PADDING_SIZE as usize) as *mut u8;
+        assert!(!extradata.is_null());
+        std::ptr::copy_nonoverlapping(video.headers.as_ptr(), extradata, size);
+        (*codecpar).extradata = extradata;
+        (*codecpar).extradata_size = size as i32;
+    }
+    ///////////////////////////////////////////////////////////////////////////
+    // AUDIO STREAM
+    ///////////////////////////////////////////////////////////////////////////
+    let mut audio = match options.audio_source.as_ref() {
+        Some(audio_path) => match AudioSource::open(audio_path, fmt_ctx) {
+            Ok(audio) => audio,
+            Err(msg) => {
+                sys::avformat_free_context(fmt_ctx);
+                return Err(msg);
+            }
+        },
+        None => None,
+    };
+    ///////////////////////////////////////////////////////////////////////////
+    // OPEN & WRITE HEADER
+    ///////////////////////////////////////////////////////////////////////////
+    if ((*(*fmt_ctx).oformat).flags & AVFMT_NOFILE as i32) == 0 {
+        let status = sys::avio_open(&mut (*fmt_ctx).pb, path.as_ptr(), AVIO_FLAG_WRITE as i32);
+        if status < 0 {
+            sys::avformat_free_context(fmt_ctx);
+            return Err(format!("could not open output file: {}", status));
+        }
+    }
+    let mut muxer_opts: *mut AVDictionary = std::ptr::null_mut();
+    if options.faststart && options.container == Container::Mp4 {
+        let key = c_str("movflags");
+        let value = c_str("+faststart");
+        sys::av_dict_set(&mut muxer_opts, key.as_ptr(), value.as_ptr(), 0);
+    }
+    let status = sys::avformat_write_header(fmt_ctx, &mut muxer_opts);
+    sys::av_dict_free(&mut muxer_opts);
+    if status < 0 {
+        sys::avio_closep(&mut (*fmt_ctx).pb);
+        sys::avformat_free_context(fmt_ctx);
+        return Err(format!("could not write header: {}", status));
+    }
+    // THE MUXER MAY HAVE PICKED ANOTHER TIME BASE
+    let output_time_base = (*stream).time_base;
+    ///////////////////////////////////////////////////////////////////////////
+    // PACKETS
+    ///////////////////////////////////////////////////////////////////////////
+    let durations = packet_durations(video);
+    let mut next_audio = audio.as_mut().and_then(|x| x.next_packet());
+    let write = |pkt: &mut AVPacket| -> Result<(), String> {
+        // TAKES OWNERSHIP OF THE PACKET DATA
+        let status = sys::av_interleaved_write_frame(fmt_ctx, pkt);
+        if status < 0 {
+            Err(format!("could not write packet: {}", status))
+        } else {
+            Ok(())
+        }
+    };
+    // STOPS AT THE FIRST ERROR, THE CLEANUP BELOW ALWAYS RUNS
+    let result = (|| -> Result<(), String> {
+        for packet in video.packets.iter() {
+            // AUDIO UP TO THIS POINT
+            while let Some(mut audio_pkt) = next_audio.take() {
+                let audio_time_base = (*audio.as_ref().expect("audio source").output_stream).time_base;
+                if sys::av_compare_ts(interleave_ts(&audio_pkt), audio_time_base, packet.dts, source_time_base) > 0 {
+                    next_audio = Some(audio_pkt);
+                    break;
+                }
+                write(&mut audio_pkt)?;
+                next_audio = audio.as_mut().and_then(|x| x.next_packet());
+            }
+            // VIDEO
+            let mut pkt: AVPacket = std::mem::zeroed();
+            sys::av_init_packet(&mut pkt);
+            assert!(sys::av_new_packet(&mut pkt, packet.data.len() as i32) == 0);
+            std::ptr::copy_nonoverlapping(packet.data.as_ptr(), pkt.data, packet.data.len());
+            pkt.stream_index = (*stream).index;
+            pkt.pts = sys::av_rescale_q(packet.pts, source_time_base, output_time_base);
+            pkt.dts = sys::av_rescale_q(packet.dts, source_time_base, output_time_base);
+            pkt.duration = sys::av_rescale_q(
+                durations.get(&packet.pts).cloned().unwrap_or(0),
+                source_time_base,
+                output_time_base,
+            );
+            if packet.keyframe {
+                pkt.flags = pkt.flags | AV_PKT_FLAG_KEY as i32;
+            }
+            write(&mut pkt)?;
+        }
+        // REMAINING AUDIO, UP TO THE END OF THE VIDEO
+        while let Some(mut audio_pkt) = next_audio.take() {
+            let audio_time_base = (*audio.as_ref().expect("audio source").output_stream).time_base;
+            if sys::av_compare_ts(interleave_ts(&audio_pkt), audio_time_base, video.timing.end_pts, source_time_base) >= 0 {
+                sys::av_packet_unref(&mut audio_pkt);
+                break;
+            }
+            write(&mut audio_pkt)?;
+            next_audio = audio.as_mut().and_then(|x| x.next_packet());
+        }
+        Ok(())
+    })();
+    // AUDIO PACKET THAT WAS READ BUT NOT WRITTEN
+    if let Some(mut audio_pkt) = next_audio.take() {
+        sys::av_packet_unref(&mut audio_pkt);
+    }
+    ///////////////////////////////////////////////////////////////////////////
+    // CLEANUP
+    ///////////////////////////////////////////////////////////////////////////
+    sys::av_write_trailer(fmt_ctx);
+    if ((*(*fmt_ctx).oformat).flags & AVFMT_NOFILE as i32) == 0 {
+        sys::avio_closep(&mut (*fmt_ctx).pb);
+    }
+    sys::avformat_free_context(fmt_ctx);
+    std::mem::drop(audio);
+    result
+}
+
+/// Like `mux_to_file`, but returns the file contents.
+///
+/// The MP4 muxer needs a seekable output (especially for faststart), so
+/// this goes through a temporary file.
+pub unsafe fn mux(video: &EncodedVideo, options: &MuxOptions) -> Result<Vec<u8>, String> {
+    let path = std::env::temp_dir().join(format!(
+        "imager-video-{}-{:p}.{}",
+        std::process::id(),
+        video,
+        options.container.extension(),
+    ));
+    let result = mux_to_file(video, &path, options)
+        .and_then(|_| std::fs::read(&path).map_err(|e| format!("{}", e)));
+    // A leftover temp file isn't worth failing over, the result is what
+    // matters.
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// PIPELINES
+///////////////////////////////////////////////////////////////////////////////
+
+/// Optimize an animated GIF into an H.264 MP4 video, keeping the GIF frame
+/// timing.
+pub fn convert_gif_to_mp4(source: &[u8]) -> Result<Vec<u8>, String> {
+    let stream = VideoBuffer::from_gif(source)
+        .map_err(|_| String::from("could not decode gif"))?;
+    let (encoded, _) = unsafe {
+        h264::opt_video(&stream, &Default::default(), &|_| ())?
+    };
+    unsafe {
+        mux(&encoded, &MuxOptions::default())
+    }
+}
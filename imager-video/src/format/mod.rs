@@ -3,6 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 pub mod decode;
 pub mod encode;
+pub mod scale;
 
 use std::collections::LinkedList;
 use std::convert::AsRef;
@@ -29,6 +30,5 @@ use ffmpeg_dev::sys::{
     AVPixelFormat_AV_PIX_FMT_YUV420P as AV_PIX_FMT_YUV420P,
 };
 
-
-
-
+/// `AV_NOPTS_VALUE`, a macro bindgen can't translate.
+pub(crate) const AV_NOPTS_VALUE: i64 = std::i64::MIN;
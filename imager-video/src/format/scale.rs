@@ -0,0 +1,194 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use ffmpeg_dev::sys;
+
+use crate::data::{Yuv420P, Frame, PixelFormat, Resolution};
+
+
+///////////////////////////////////////////////////////////////////////////////
+// OPTIONS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleFilter {
+    Bilinear,
+    /// Used for upscaling the distorted stream before VMAF.
+    Bicubic,
+    /// Used for downscaling.
+    Lanczos,
+}
+
+impl ScaleFilter {
+    fn flags(&self) -> i32 {
+        let filter = match self {
+            ScaleFilter::Bilinear => sys::SWS_BILINEAR,
+            ScaleFilter::Bicubic => sys::SWS_BICUBIC,
+            ScaleFilter::Lanczos => sys::SWS_LANCZOS,
+        };
+        (filter | sys::SWS_ACCURATE_RND) as i32
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// The largest size within the given bounds with the same aspect ratio,
+/// rounded down to even dimensions (as required for 4:2:0).
+pub fn fit_within((width, height): (u32, u32), max_size: &Resolution) -> (u32, u32) {
+    let even = |x: f64| ((x as u32) / 2 * 2).max(2);
+    if width <= max_size.width && height <= max_size.height {
+        return (width, height);
+    }
+    let ratio = {
+        let x = (max_size.width as f64) / (width as f64);
+        let y = (max_size.height as f64) / (height as f64);
+        x.min(y)
+    };
+    (even(width as f64 * ratio), even(height as f64 * ratio))
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// SCALE
+///////////////////////////////////////////////////////////////////////////////
+
+/// Plane pointers and strides (in bytes) of a tightly packed planar buffer.
+unsafe fn plane_pointers(
+    data: *mut u8,
+    dimensions: (u32, u32),
+    format: PixelFormat,
+) -> ([*mut u8; 3], [i32; 3]) {
+    let mut planes = [std::ptr::null_mut(); 3];
+    let mut strides = [0; 3];
+    let mut offset = 0;
+    for plane in 0 .. 3 {
+        let (width, _) = format.plane_dimensions(dimensions, plane);
+        planes[plane] = data.add(offset);
+        strides[plane] = (width as usize * format.bytes_per_sample()) as i32;
+        offset = offset + format.plane_size(dimensions, plane);
+    }
+    (planes, strides)
+}
+
+unsafe fn sws_convert(
+    src_planes: *const *const u8,
+    src_stride: *const i32,
+    src_format: sys::AVPixelFormat,
+    (src_width, src_height): (u32, u32),
+    output: &mut Frame,
+    filter: ScaleFilter,
+) {
+    let (dst_planes, dst_stride) = plane_pointers(
+        output.data.as_mut_ptr(),
+        output.dimensions(),
+        output.format,
+    );
+    let ctx = sys::sws_getContext(
+        src_width as i32,
+        src_height as i32,
+        src_format,
+        output.width as i32,
+        output.height as i32,
+        output.format.to_av(),
+        filter.flags(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        std::ptr::null(),
+    );
+    assert!(!ctx.is_null());
+    let rows = sys::sws_scale(
+        ctx,
+        src_planes,
+        src_stride,
+        0,
+        src_height as i32,
+        dst_planes.as_ptr(),
+        dst_stride.as_ptr(),
+    );
+    sys::sws_freeContext(ctx);
+    assert!(rows == output.height as i32);
+}
+
+/// Resize and/or change the pixel format with libswscale.
+pub fn convert(
+    source: &Frame,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    filter: ScaleFilter,
+) -> Frame {
+    // CHECKS
+    if source.dimensions() == (width, height) && source.format == format {
+        return source.clone();
+    }
+    // SETUP
+    let mut output = Frame {
+        width,
+        height,
+        format,
+        data: vec![0; format.frame_size((width, height))],
+    };
+    // GO!
+    unsafe {
+        let (src_planes, src_stride) = plane_pointers(
+            source.data.as_ptr() as *mut u8,
+            source.dimensions(),
+            source.format,
+        );
+        sws_convert(
+            src_planes.as_ptr() as *const *const u8,
+            src_stride.as_ptr(),
+            source.format.to_av(),
+            source.dimensions(),
+            &mut output,
+            filter,
+        );
+    };
+    // DONE
+    output
+}
+
+/// Copy a decoded picture, converting it to the given format.
+pub unsafe fn convert_av_frame(frame: *const sys::AVFrame, format: PixelFormat) -> Frame {
+    assert!(!frame.is_null());
+    let (width, height) = ((*frame).width as u32, (*frame).height as u32);
+    let mut output = Frame {
+        width,
+        height,
+        format,
+        data: vec![0; format.frame_size((width, height))],
+    };
+    sws_convert(
+        (*frame).data.as_ptr() as *const *const u8,
+        (*frame).linesize.as_ptr(),
+        (*frame).format,
+        (width, height),
+        &mut output,
+        ScaleFilter::Bicubic,
+    );
+    output
+}
+
+/// Resize with libswscale, to exactly the given (even) dimensions.
+pub fn scale(source: &Yuv420P, width: u32, height: u32, filter: ScaleFilter) -> Yuv420P {
+    // CHECKS
+    if source.dimensions() == (width, height) {
+        return source.clone();
+    }
+    assert!(width % 2 == 0 && height % 2 == 0);
+    // GO!
+    let output = convert(
+        &Frame::from(source.clone()),
+        width,
+        height,
+        PixelFormat::YUV420P,
+        filter,
+    );
+    // DONE
+    Yuv420P {
+        width,
+        height,
+        data: output.data,
+    }
+}
@@ -3,38 +3,198 @@ pub mod codec;
 pub mod format;
 pub mod data;
 pub mod tool;
+#[path = "../../imager/src/shared.rs"]
+pub mod shared;
 
-use data::{VideoBuffer, Yuv420P};
-
-fn format() {
-    let path = "assets/samples/test.h264";
-    let video = VideoBuffer::open_video(path).expect("decode video file");
-    println!("video frames: {}", video.as_frames().len());
-    video
-        .as_frames()
-        .get(50)
-        .expect("get frame")
-        .save("assets/output/test.yuv");
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+use data::{VideoBuffer, Yuv420P, Resolution};
+use codec::h264::{H264Options, OptOptions, ShotReport};
+use format::encode::{Container, MuxOptions};
+use format::decode::FrameReader;
+
+///////////////////////////////////////////////////////////////////////////////
+// CLI FRONTEND - INTERNAL HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+fn is_gif(path: &PathBuf) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase() == "gif")
+        .unwrap_or(false)
 }
 
-fn encode_from_dir() {
-    let path = "assets/samples/dump-2";
-    let stream = VideoBuffer::open_image_dir(path).expect("load source dir");
-    let output = unsafe {codec::h264::encode(&stream, 0.0).expect("encode to h264")};
-    std::fs::write("assets/output/dump2.h264", &output);
+fn open_input(path: &PathBuf) -> VideoBuffer {
+    if path.is_dir() {
+        return VideoBuffer::open_image_dir(path).expect("load source dir");
+    }
+    if is_gif(path) {
+        VideoBuffer::open_gif(path).expect("decode gif file")
+    } else {
+        VideoBuffer::open_video(path).expect("decode video file")
+    }
 }
 
+///////////////////////////////////////////////////////////////////////////////
+// CLI FRONTEND
+///////////////////////////////////////////////////////////////////////////////
+
+/// The Imager Video CLI Interface
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(name = "imager-video")]
+pub struct Command {
+    /// Input video, GIF, or directory of (numbered) image frames.
+    #[structopt(short, long, parse(from_os_str))]
+    input: PathBuf,
+
+    /// Save the result to this file path.
+    #[structopt(short, long, parse(from_os_str))]
+    output: PathBuf,
+
+    /// Output container, either `mp4` or `mkv`.
+    ///
+    /// Defaults to the output file extension, or `mp4`.
+    #[structopt(short, long)]
+    container: Option<Container>,
+
+    /// Minimum VMAF score for every shot.
+    ///
+    /// Defaults to a per-class threshold, picked by the classifier.
+    #[structopt(long)]
+    target_vmaf: Option<f64>,
+
+    /// Encoder preset.
+    ///
+    /// One of `quality`, `speed`, `mobile`, or an x264 preset name.
+    #[structopt(long, default_value = "quality")]
+    preset: H264Options,
+
+    /// Downscale the video if its resolution exceeds the given size.
+    #[structopt(long)]
+    max_size: Option<Resolution>,
+
+    /// Drop frames if the frame rate exceeds the given frames per second.
+    #[structopt(long)]
+    max_fps: Option<f64>,
+
+    /// Save the per-shot reports to this file, as JSON.
+    ///
+    /// One entry per detected shot (frame range, class, CRF and VMAF score).
+    /// The CRF is picked per shot, so there are no per-frame reports.
+    #[structopt(long, parse(from_os_str))]
+    log_file: Option<PathBuf>,
+
+    /// Dump the chosen sample encode of every shot, and the shot reports,
+    /// to this directory.
+    ///
+    /// Internal. No stability guarantees.
+    #[structopt(long, parse(from_os_str))]
+    debug_dir: Option<PathBuf>,
+}
+
+impl Command {
+    /// Decode the input video as the frames are needed, see
+    /// `codec::h264::opt_stream`.
+    fn stream_input(&self) -> Box<dyn Iterator<Item = (Yuv420P, i64)>> {
+        let reader = FrameReader::open(&self.input).expect("decode video file");
+        let time_base = reader.time_base();
+        let mut frames: Box<dyn Iterator<Item = (Yuv420P, i64)>> = Box::new(reader.timed());
+        if let Some(max_fps) = self.max_fps {
+            let mut keep = data::frame_rate_filter(time_base, max_fps);
+            frames = Box::new(frames.filter(move |(_, x)| keep(*x)));
+        }
+        if let Some(max_size) = self.max_size.clone() {
+            frames = Box::new(frames.map(move |(frame, x)| {
+                if frame.width <= max_size.width && frame.height <= max_size.height {
+                    return (frame, x);
+                }
+                (frame.resize(max_size.width, max_size.height), x)
+            }));
+        }
+        frames
+    }
+    pub fn run(&self) {
+        // SETUP
+        let container = self.container.clone().unwrap_or_else(|| {
+            self.output
+                .extension()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.parse::<Container>().ok())
+                .unwrap_or_default()
+        });
+        if let Some(debug_dir) = self.debug_dir.as_ref() {
+            std::fs::create_dir_all(debug_dir).expect("create debug dir");
+        }
+        let options = OptOptions {
+            h264: self.preset.clone(),
+            target_vmaf: self.target_vmaf,
+            debug_dir: self.debug_dir.clone(),
+        };
+        let is_video_input = self.input.is_file() && !is_gif(&self.input);
+        let progress = |report: &ShotReport| {
+            eprintln!(
+                "[shot] frames {}..{} crf={} vmaf={:.1}{}",
+                report.shot.start,
+                report.shot.end,
+                report.crf,
+                report.vmaf,
+                if report.passed {""} else {" (below target)"},
+            );
+        };
+        // GO!
+        let (encoded, shots_report) = if is_video_input {
+            // VIDEOS ARE STREAMED, SO LONG INPUTS DON'T HAVE TO FIT IN MEMORY
+            let reader = FrameReader::open(&self.input).expect("decode video file");
+            codec::h264::check_opt_format(reader.format()).expect("unsupported input");
+            let time_base = reader.time_base();
+            std::mem::drop(reader);
+            unsafe {
+                codec::h264::opt_stream(|| self.stream_input(), time_base, &options, &progress)
+                    .expect("opt encode failed")
+            }
+        } else {
+            // GIFS AND IMAGE DIRECTORIES ARE DECODED UP FRONT
+            let mut stream = open_input(&self.input);
+            if let Some(max_size) = self.max_size.as_ref() {
+                stream = stream.max_size(max_size);
+            }
+            if let Some(max_fps) = self.max_fps {
+                stream = stream.max_frame_rate(max_fps);
+            }
+            unsafe {
+                codec::h264::opt_video(&stream, &options, &progress).expect("opt encode failed")
+            }
+        };
+        // SAVE
+        // AUDIO IS COPIED AS IS, WHEN THE INPUT HAS ANY
+        let mux_options = MuxOptions {
+            container,
+            audio_source: if is_video_input {Some(self.input.clone())} else {None},
+            ..Default::default()
+        };
+        if let Some(parent_dir) = self.output.parent() {
+            if !parent_dir.as_os_str().is_empty() && !parent_dir.exists() {
+                std::fs::create_dir_all(&parent_dir).expect("create parent dir");
+            }
+        }
+        unsafe {
+            format::encode::mux_to_file(&encoded, &self.output, &mux_options)
+                .expect("failed to write output file");
+        };
+        // SAVE LOG FILE
+        if let Some(log_path) = self.log_file.clone() {
+            let output_log = serde_json::to_string_pretty(&shots_report).expect("to json str failed");
+            std::fs::write(log_path, output_log).expect("failed to write log file");
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// MAIN
+///////////////////////////////////////////////////////////////////////////////
+
 fn main() {
-    codec::h264::run();
-    // encode_from_dir();
-    // tool::vmaf::run();
-    // let source = Yuv420P::open_image("assets/samples/3183183.jpg").expect("load source image");
-    // let result = source.to_rgba_image();
-    // result.save("assets/output/test.jpeg").expect("save image");
-
-    // unsafe {
-    //     let source = Yuv420P::open_image("assets/samples/3183183.jpg").expect("load source image");
-    //     let result = data::convert_to_rgba_using_webp(&source);
-    //     result.save("assets/output/test.jpeg").expect("save image");
-    // };
+    let cmd = Command::from_args();
+    cmd.run();
 }
@@ -1,2 +1,3 @@
 pub mod vmaf;
-pub mod classifier;
\ No newline at end of file
+pub mod classifier;
+pub mod scene;
\ No newline at end of file
@@ -0,0 +1,120 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use crate::data::Yuv420P;
+
+
+///////////////////////////////////////////////////////////////////////////////
+// SETTINGS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Mean absolute luma difference (normalized to `0..1`) between two
+/// consecutive frames, above which a new shot starts.
+pub const SCENE_CUT_THRESHOLD: f64 = 0.12;
+
+/// Cuts closer than this (in frames) to the previous cut are ignored,
+/// e.g. flashes and fast pans.
+pub const MIN_SHOT_LENGTH: usize = 12;
+
+/// Only every n-th luma sample (in both directions) is compared.
+const SUBSAMPLE: usize = 4;
+
+///////////////////////////////////////////////////////////////////////////////
+// DATA TYPES
+///////////////////////////////////////////////////////////////////////////////
+
+/// A range of frames, `end` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Shot {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Shot {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// SCENE-CUT DETECTION
+///////////////////////////////////////////////////////////////////////////////
+
+fn luma_difference(a: &Yuv420P, b: &Yuv420P) -> f64 {
+    assert!(a.dimensions() == b.dimensions());
+    let width = a.width as usize;
+    let (a, b) = (a.y(), b.y());
+    let mut total: u64 = 0;
+    let mut count: u64 = 0;
+    for row in (0 .. a.len() / width).step_by(SUBSAMPLE) {
+        for col in (0 .. width).step_by(SUBSAMPLE) {
+            let ix = row * width + col;
+            total = total + (a[ix] as i32 - b[ix] as i32).abs() as u64;
+            count = count + 1;
+        }
+    }
+    (total as f64) / (count.max(1) as f64 * 255.0)
+}
+
+/// Incremental `detect_shots`, only the previous frame is kept around.
+#[derive(Debug, Default)]
+pub struct ShotDetector {
+    previous: Option<Yuv420P>,
+    start: usize,
+    frames: usize,
+    shots: Vec<Shot>,
+}
+
+impl ShotDetector {
+    pub fn new() -> Self {
+        ShotDetector::default()
+    }
+    pub fn push(&mut self, frame: Yuv420P) {
+        let diff = self.previous
+            .as_ref()
+            .map(|previous| luma_difference(previous, &frame));
+        self.advance(diff);
+        self.previous = Some(frame);
+    }
+    /// `diff` is between the previous frame and this one, if any.
+    fn advance(&mut self, diff: Option<f64>) {
+        let cut = self.frames;
+        if let Some(diff) = diff {
+            if diff >= SCENE_CUT_THRESHOLD && cut - self.start >= MIN_SHOT_LENGTH {
+                self.shots.push(Shot {start: self.start, end: cut});
+                self.start = cut;
+            }
+        }
+        self.frames = self.frames + 1;
+    }
+    /// Shots covering every pushed frame.
+    pub fn finish(mut self) -> Vec<Shot> {
+        if self.frames > self.start {
+            self.shots.push(Shot {start: self.start, end: self.frames});
+        }
+        self.shots
+    }
+}
+
+/// Split the stream into shots, covering every frame.
+pub fn detect_shots(frames: &[Yuv420P]) -> Vec<Shot> {
+    if frames.is_empty() {
+        return Vec::new();
+    }
+    // DIFFERENCES, `diffs[i]` IS BETWEEN FRAME `i` AND `i + 1`
+    let diffs = frames
+        .par_windows(2)
+        .map(|x| luma_difference(&x[0], &x[1]))
+        .collect::<Vec<_>>();
+    // CUTS
+    let mut detector = ShotDetector::new();
+    detector.advance(None);
+    for diff in diffs {
+        detector.advance(Some(diff));
+    }
+    // DONE
+    detector.finish()
+}
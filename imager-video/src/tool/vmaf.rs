@@ -2,52 +2,65 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use std::path::PathBuf;
-use std::sync::{Mutex, MutexGuard};
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int};
 use libc::{size_t, c_float, c_void};
-use lazy_static::lazy_static;
 
-use crate::data::{Yuv420P, VideoBuffer};
+use crate::data::{Yuv420P, Frame, PixelFormat, VideoBuffer};
+use crate::format::scale::{self, ScaleFilter};
+pub use crate::shared::{
+    Sampling,
+    PoolMethod,
+    Gate,
+    Features,
+    ConfidenceInterval,
+    FrameMetrics,
+    VmafReport,
+    percentile,
+};
+use crate::shared::{parse_log, ThreadLocale};
 
 
 ///////////////////////////////////////////////////////////////////////////////
 // VMAF CONTEXT
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Frames are pulled from the streams on demand, nothing is buffered here.
+pub type FrameStream<'a> = Box<dyn Iterator<Item = Frame> + 'a>;
+
 struct Context<'a> {
-    stream1: &'a mut VideoBuffer,
-    stream2: &'a mut VideoBuffer,
+    stream1: FrameStream<'a>,
+    stream2: FrameStream<'a>,
+    dimensions: (u32, u32),
+    format: PixelFormat,
     frames_set: bool,
 }
 
-lazy_static! {
-    static ref VMAF_LOCK: Mutex<()> = {
-        Mutex::new(())
-    };
-}
-
 ///////////////////////////////////////////////////////////////////////////////
 // VMAF CALLBACK
 ///////////////////////////////////////////////////////////////////////////////
 
 
+/// Luma only, high bit depth samples are scaled down to the 8-bit range
+/// (as libvmaf's own readers do).
 unsafe fn fill_vmaf_buffer(
     mut output: *mut c_float,
     output_stride: c_int,
-    source: &Yuv420P,
+    source: &Frame,
 ) {
     let (width, height) = source.dimensions();
-    let src_linesize = source.width as usize;
     let dest_stride = output_stride as usize;
-    let mut source_ptr: *const u8 = source.y().as_ptr();
-    for y in 0..height {
-        for x in 0..width {
-            let s1_px: u8 = *(source_ptr.offset(x as isize));
-            let s1_px: c_float = s1_px as c_float;
-            *(output.offset(x as isize)) = s1_px
+    let high_depth = source.format.is_high_depth();
+    for row in source.plane(0).chunks(source.plane_stride(0)) {
+        for x in 0..width as usize {
+            let px: c_float = if high_depth {
+                let sample = u16::from_le_bytes([row[x * 2], row[x * 2 + 1]]);
+                (sample as c_float) / 4.0
+            } else {
+                row[x] as c_float
+            };
+            *(output.offset(x as isize)) = px;
         }
-        source_ptr = source_ptr.add(src_linesize / std::mem::size_of_val(&*source_ptr));
         output = output.add(dest_stride / std::mem::size_of_val(&*output));
     }
 }
@@ -71,6 +84,10 @@ unsafe extern "C" fn read_frame(
     // NEXT FRAME OR DONE
     match (vmaf_ctx.stream1.next(), vmaf_ctx.stream2.next()) {
         (Some(frame1), Some(frame2)) => {
+            assert!(frame1.dimensions() == vmaf_ctx.dimensions);
+            assert!(frame2.dimensions() == vmaf_ctx.dimensions);
+            assert!(frame1.format == vmaf_ctx.format);
+            assert!(frame2.format == vmaf_ctx.format);
             fill_vmaf_buffer(source1_out, out_stride, &frame1);
             fill_vmaf_buffer(source2_out, out_stride, &frame2);
         }
@@ -91,15 +108,20 @@ unsafe extern "C" fn read_frame(
 // VMAF PIPELINE
 ///////////////////////////////////////////////////////////////////////////////
 
-pub unsafe fn vmaf_controller<'a>(stream1: &'a mut VideoBuffer, stream2: &'a mut VideoBuffer) -> f64 {
-    // CHECKS
-    assert!(stream1.dimensions() == stream2.dimensions());
-
+/// `None` if libvmaf's log can't be read back.
+pub unsafe fn vmaf_controller<'a>(
+    stream1: FrameStream<'a>,
+    stream2: FrameStream<'a>,
+    dimensions: (u32, u32),
+    format: PixelFormat,
+) -> Option<Vec<FrameMetrics>> {
     // INIT VMAF CONTEXT
-    let (width, height) = stream1.dimensions();
+    let (width, height) = dimensions;
     let vmaf_ctx = Box::new(Context {
         stream1: stream1,
         stream2: stream2,
+        dimensions,
+        format,
         frames_set: false
     });
     let vmaf_ctx = Box::into_raw(vmaf_ctx);
@@ -111,9 +133,15 @@ pub unsafe fn vmaf_controller<'a>(stream1: &'a mut VideoBuffer, stream2: &'a mut
         .expect("PathBuf to str failed")
         .to_owned();
     let model_path = CString::new(model_path).expect("CString::new failed");
-    let mut fmt = CString::new(String::from("yuv420p")).expect("CString::new failed");
-    let log_path: *mut c_char = std::ptr::null_mut();
-    let log_fmt: *mut c_char = std::ptr::null_mut();
+    let mut fmt = CString::new(format.vmaf_format()).expect("CString::new failed");
+    // PER-FRAME SCORES ONLY COME THROUGH THE LOG FILE
+    let log_file = std::env::temp_dir().join(format!(
+        "imager-video-vmaf-{}-{:p}.json",
+        std::process::id(),
+        vmaf_ctx,
+    ));
+    let log_path = CString::new(log_file.to_str().expect("PathBuf to str failed")).expect("CString::new failed");
+    let log_fmt = CString::new("json").expect("CString::new failed");
     let disable_clip = 0;
     let disable_avx = 0;
     let enable_transform = 0;
@@ -135,8 +163,8 @@ pub unsafe fn vmaf_controller<'a>(stream1: &'a mut VideoBuffer, stream2: &'a mut
         Some(read_frame),
         vmaf_ctx as *mut libc::c_void,
         model_path.as_ptr() as *mut c_char,
-        log_path,
-        log_fmt,
+        log_path.as_ptr() as *mut c_char,
+        log_fmt.as_ptr() as *mut c_char,
         disable_clip,
         disable_avx,
         enable_transform,
@@ -156,33 +184,115 @@ pub unsafe fn vmaf_controller<'a>(stream1: &'a mut VideoBuffer, stream2: &'a mut
     // CLEANUP
     let mut vmaf_ctx = Box::from_raw(vmaf_ctx);
     std::mem::drop(vmaf_ctx);
+    let log = std::fs::read_to_string(&log_file).ok();
+    let _ = std::fs::remove_file(&log_file);
 
     // DONE
-    vmaf_score
+    Some(parse_log(&log?))
 }
 
-pub fn get_report(stream1: &VideoBuffer, stream2: &VideoBuffer) -> f64 {
+/// Per-frame metrics of two frame streams of equal length, e.g. a pair of
+/// `FrameReader`s, one frame pair at a time. The distorted (second) stream
+/// is converted to the resolution and pixel format of the reference if
+/// needed. `None` if libvmaf's log can't be read back.
+pub fn get_frame_metrics<'a, I1, I2>(stream1: I1, stream2: I2) -> Option<Vec<FrameMetrics>>
+where
+    I1: Iterator<Item = Frame> + 'a,
+    I2: Iterator<Item = Frame> + 'a,
+{
     // SETUP
-    let mut stream1 = stream1.as_fresh_cursor();
-    let mut stream2 = stream2.as_fresh_cursor();
-    assert!(stream1.as_frames().len() == stream2.as_frames().len());
-    // LOCK
-    let lock = VMAF_LOCK.lock().expect("failed to lock vmaf work");
+    let mut stream1 = stream1.peekable();
+    let (dimensions, format) = match stream1.peek() {
+        Some(first) => (first.dimensions(), first.format),
+        None => return Some(Vec::new()),
+    };
+    // COMPARE AT THE RESOLUTION OF THE REFERENCE
+    let (width, height) = dimensions;
+    let stream2 = stream2.map(move |x| {
+        scale::convert(&x, width, height, format, ScaleFilter::Bicubic)
+    });
+    // THIS THREAD ONLY, SEE `ThreadLocale`
+    let locale = ThreadLocale::c();
     // GO!
-    let score = unsafe {vmaf_controller(&mut stream1, &mut stream2)};
-    // UNLOCK
-    std::mem::drop(lock);
-    // UPDATE
-    stream1.set_cursor(0);
-    stream2.set_cursor(0);
+    let metrics = unsafe {
+        vmaf_controller(Box::new(stream1), Box::new(stream2), dimensions, format)
+    };
+    // RESTORE
+    std::mem::drop(locale);
     // DONE
-    score
+    metrics
+}
+
+/// Per-frame scores, see `get_frame_metrics`.
+pub fn get_frame_scores<'a, I1, I2>(stream1: I1, stream2: I2) -> Option<Vec<f64>>
+where
+    I1: Iterator<Item = Frame> + 'a,
+    I2: Iterator<Item = Frame> + 'a,
+{
+    let metrics = get_frame_metrics(stream1, stream2)?;
+    Some(metrics.into_iter().map(|x| x.vmaf).collect())
+}
+
+/// Mean score of two frame streams of equal length, see `get_frame_metrics`.
+/// Empty streams (or an unreadable libvmaf log) score zero.
+pub fn get_report_frames<'a, I1, I2>(stream1: I1, stream2: I2) -> f64
+where
+    I1: Iterator<Item = Frame> + 'a,
+    I2: Iterator<Item = Frame> + 'a,
+{
+    get_frame_metrics(stream1, stream2)
+        .and_then(|metrics| {
+            let frames = (0 .. metrics.len()).collect::<Vec<_>>();
+            VmafReport::new(frames, metrics, PoolMethod::Mean)
+        })
+        .map(|x| x.mean)
+        .unwrap_or(0.0)
+}
+
+pub fn get_report_streams<'a, I1, I2>(stream1: I1, stream2: I2) -> f64
+where
+    I1: Iterator<Item = Yuv420P> + 'a,
+    I2: Iterator<Item = Yuv420P> + 'a,
+{
+    get_report_frames(stream1.map(Frame::from), stream2.map(Frame::from))
+}
+
+pub fn get_report(stream1: &VideoBuffer, stream2: &VideoBuffer) -> f64 {
+    assert!(stream1.as_frames().len() == stream2.as_frames().len());
+    get_report_streams(
+        stream1.as_frames().iter().cloned(),
+        stream2.as_frames().iter().cloned(),
+    )
+}
+
+/// Score the sampled frames of both streams. Streams of different lengths
+/// are only compared up to the shorter one. `None` if the sampling picks no
+/// frames, or if libvmaf's log can't be read back.
+pub fn get_report_sampled(
+    stream1: &VideoBuffer,
+    stream2: &VideoBuffer,
+    sampling: &Sampling,
+    pool: PoolMethod,
+) -> Option<VmafReport> {
+    let total = stream1.as_frames().len().min(stream2.as_frames().len());
+    let mut frames = Vec::<usize>::new();
+    let mut metrics = Vec::<FrameMetrics>::new();
+    for run in sampling.runs(total) {
+        let pick = |xs: &'_ [Yuv420P]| run
+            .iter()
+            .map(|ix| Frame::from(xs[*ix].clone()))
+            .collect::<Vec<_>>();
+        let (frames1, frames2) = (pick(stream1.as_frames()), pick(stream2.as_frames()));
+        metrics.extend(get_frame_metrics(frames1.into_iter(), frames2.into_iter())?);
+        frames.extend(run);
+    }
+    VmafReport::new(frames, metrics, pool)
 }
 
 pub fn run() {
-    let mut stream1 = VideoBuffer::open_video("assets/samples/test.h264").expect("source file");
-    let mut stream2 = VideoBuffer::open_video("assets/samples/test.h264").expect("source file");
+    let stream1 = crate::format::decode::FrameReader::open("assets/samples/test.h264").expect("source file");
+    let stream2 = crate::format::decode::FrameReader::open("assets/samples/test.h264").expect("source file");
 
-    let score = get_report(&stream1, &stream2);
+    let score = get_report_streams(stream1, stream2);
     println!("score: {}", score);
-}
\ No newline at end of file
+}
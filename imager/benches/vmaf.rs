@@ -0,0 +1,50 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use criterion::{criterion_group, criterion_main, Criterion};
+use image::{DynamicImage, FilterType};
+use rayon::prelude::*;
+
+use imager::data::VideoBuffer;
+use imager::codec::jpeg;
+use imager::vmaf;
+
+/// Mirrors a quality search, i.e. many derivatives scored against one
+/// source. `compute_vmaf` calls don't share a lock, so `parallel` should
+/// scale with the number of cores.
+fn setup() -> (VideoBuffer, Vec<VideoBuffer>) {
+    let source = image::load_from_memory(include_bytes!("../assets/test/1.jpeg"))
+        .expect("load test image")
+        .resize(600, 600, FilterType::Lanczos3);
+    let source = imager::data::ensure_even_reslution(&source);
+    let derivatives = (1 ..= 8)
+        .map(|x| {
+            let compressed = unsafe {jpeg::encode(&source, x * 10)};
+            VideoBuffer::from_jpeg(&compressed).expect("to VideoBuffer")
+        })
+        .collect::<Vec<_>>();
+    let source = VideoBuffer::from_image(&source).expect("to VideoBuffer");
+    (source, derivatives)
+}
+
+fn vmaf_benchmark(c: &mut Criterion) {
+    let (source, derivatives) = setup();
+    let mut group = c.benchmark_group("vmaf");
+    group.sample_size(10);
+    group.bench_function("sequential", |b| b.iter(|| {
+        derivatives
+            .iter()
+            .map(|x| vmaf::get_report(&source, x))
+            .collect::<Vec<_>>()
+    }));
+    group.bench_function("parallel", |b| b.iter(|| {
+        derivatives
+            .par_iter()
+            .map(|x| vmaf::get_report(&source, x))
+            .collect::<Vec<_>>()
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, vmaf_benchmark);
+criterion_main!(benches);
@@ -1,27 +1,76 @@
 use std::convert::AsRef;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 use image::{DynamicImage, GenericImage, GenericImageView, ImageFormat};
 use either::{Either, Either::*};
 use serde::{Serialize, Deserialize};
 
-use crate::data::{Resolution, OutputFormat};
-use crate::codec::jpeg;
-use crate::codec::png;
+use crate::classifier::{self, Class, Meta};
+use crate::data::{Resolution, OutputFormat, Animation};
+use crate::codec::gif;
+use crate::codec::png::{self, PngOptions};
 use crate::codec::webp;
+use crate::codec::webp::encode::lossy::WebpOptions;
+use crate::codec::encoder::{Encoder, JpegEncoder, PngEncoder, WebpEncoder};
+use crate::vmaf::{VmafOptions, VmafModel};
+use crate::metrics::{Metric, QualityMetric, QualityOptions, Cached};
 
 pub struct OptJob {
     source: DynamicImage,
     source_format: ImageFormat,
+    /// Size of the encoded source, in bytes.
+    source_size: usize,
+    /// Only set for inputs with more than one frame, `source` is then
+    /// the first frame.
+    animation: Option<Animation>,
     output_format: OutputFormat,
     max_size: Option<Resolution>,
+    webp_options: WebpOptions,
+    png_options: PngOptions,
+    quality: QualityOptions,
+    /// Takes precedence over `quality.metric`.
+    custom_metric: Option<Arc<dyn QualityMetric>>,
+    /// Takes precedence over `output_format`.
+    custom_encoder: Option<Arc<dyn Encoder>>,
+    fast_mode: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OutMeda {
-    pub input_class: crate::classifier::Class,
+    pub input_class: Class,
+    /// The classifier features `input_class` is derived from.
+    pub class_meta: Meta,
     pub input_path: Option<PathBuf>,
     pub output_path: Option<PathBuf>,
+    /// In bytes.
+    pub input_size: usize,
+    /// In bytes.
+    pub output_size: usize,
+    /// `input_size / output_size`.
+    pub compression_ratio: f64,
+    pub input_dimensions: (u32, u32),
+    /// Differs from `input_dimensions` when downscaled to `max_size`.
+    pub output_dimensions: (u32, u32),
+    /// Score of the chosen metric, VMAF by default. `None` when the output
+    /// wasn't scored, e.g. lossless PNGs.
     pub vmaf_score: Option<f64>,
+    /// `false` if nothing passed the threshold, and the output is the
+    /// optimizer's high quality fallback.
+    pub passed: bool,
+    /// The chosen JPEG/WebP (or custom encoder) quality. `None` for WebP
+    /// fast mode, see `target_psnr`.
+    pub quality: Option<u32>,
+    /// The PSNR libwebp's rate control converged on, WebP fast mode only.
+    pub target_psnr: Option<f64>,
+    /// PNG only, `None` if the lossless re-encode was smaller.
+    pub palette_size: Option<usize>,
+    /// PNG only.
+    pub png_mode: Option<png::ImageMode>,
+    /// Number of encodes that were scored.
+    pub probes: usize,
+    /// Seconds spent in `OptJob::run`.
+    pub wall_time: f64,
     pub extreme_mode: Option<bool>,
 }
 
@@ -31,39 +80,51 @@ impl OptJob {
         OptJob::new(&source)
     }
     pub fn new(source: &[u8]) -> Result<Self, ()> {
+        let source_size = source.len();
         let source_format = ::image::guess_format(source).map_err(drop)?;
+        let animation = match source_format {
+            ImageFormat::GIF => Some(gif::decode(source).map_err(drop)?),
+            ImageFormat::WEBP if webp::anim::is_animated(source) => {
+                Some(webp::anim::decode(source).map_err(drop)?)
+            }
+            _ => None
+        };
+        let animation = animation
+            .filter(|x| x.is_animated())
+            .map(|x| x.map_frames(crate::data::ensure_even_reslution));
         let output_format = match source_format {
+            _ if animation.is_some() => OutputFormat::Webp,
             ImageFormat::JPEG => OutputFormat::Jpeg,
             ImageFormat::PNG => OutputFormat::Png,
             ImageFormat::WEBP => OutputFormat::Webp,
             _ => OutputFormat::Jpeg
         };
-        match source_format {
-            ImageFormat::WEBP => {
-                let source = webp::decode::decode(source);
-                let source = crate::data::ensure_even_reslution(&source);
-                Ok(OptJob {
-                    output_format,
-                    source,
-                    source_format,
-                    max_size: None,
-                })
-            }
-            _ => {
-                let source = ::image::load_from_memory_with_format(
+        let source = match (&animation, source_format) {
+            (Some(animation), _) => animation.first_frame().clone(),
+            (None, ImageFormat::WEBP) => webp::decode::decode(source),
+            (None, _) => {
+                ::image::load_from_memory_with_format(
                         source,
                         source_format,
                     )
-                    .map_err(drop)?;
-                let source = crate::data::ensure_even_reslution(&source);
-                Ok(OptJob {
-                    output_format,
-                    source,
-                    source_format,
-                    max_size: None,
-                })
+                    .map_err(drop)?
             }
-        }
+        };
+        let source = crate::data::ensure_even_reslution(&source);
+        Ok(OptJob {
+            output_format,
+            source,
+            source_format,
+            source_size,
+            animation,
+            max_size: None,
+            webp_options: WebpOptions::default(),
+            png_options: PngOptions::default(),
+            quality: QualityOptions::default(),
+            custom_metric: None,
+            custom_encoder: None,
+            fast_mode: false,
+        })
     }
     pub fn output_format(&mut self, output_format: OutputFormat) {
         self.output_format = output_format;
@@ -71,53 +132,210 @@ impl OptJob {
     pub fn max_size(&mut self, max_size: Resolution) {
         self.max_size = Some(max_size);
     }
+    pub fn webp_options(&mut self, webp_options: WebpOptions) {
+        self.webp_options = webp_options;
+    }
+    pub fn png_options(&mut self, png_options: PngOptions) {
+        self.png_options = png_options;
+    }
+    /// How VMAF scores are pooled, and which aggregate (e.g. the worst
+    /// frame) has to pass the thresholds. Fails (and keeps the current
+    /// options) if libvmaf can't run with them, see `VmafOptions::validate`.
+    pub fn vmaf_options(&mut self, vmaf_options: VmafOptions) -> Result<(), String> {
+        vmaf_options.validate()?;
+        self.quality.vmaf = vmaf_options;
+        Ok(())
+    }
+    /// The 4K model by default, the strictest of the bundled models. Fails
+    /// like `vmaf_options`, e.g. a model without confidence intervals when
+    /// those are enabled.
+    pub fn vmaf_model(&mut self, vmaf_model: VmafModel) -> Result<(), String> {
+        self.vmaf_options(VmafOptions {
+            model: vmaf_model,
+            ..self.quality.vmaf.clone()
+        })
+    }
+    /// The metric that drives the quality search, VMAF by default.
+    pub fn metric(&mut self, metric: Metric) {
+        self.quality.metric = metric;
+    }
+    /// Overrides the metric's default threshold, e.g. the per-class VMAF
+    /// thresholds.
+    pub fn threshold(&mut self, threshold: f64) {
+        self.quality.threshold = Some(threshold);
+    }
+    /// Plug in any other metric, instead of one of the built-in ones.
+    pub fn custom_metric(&mut self, metric: Arc<dyn QualityMetric>) {
+        self.custom_metric = Some(metric);
+    }
+    /// Plug in any other encoder, which is then optimized with its
+    /// `Encoder::search`, by default the generic search in
+    /// `codec::encoder::optimize`. Animations only get their
+    /// first frame.
+    pub fn custom_encoder(&mut self, encoder: Arc<dyn Encoder>) {
+        self.custom_encoder = Some(encoder);
+    }
+    /// Trade a few bytes for an order of magnitude less work.
+    ///
+    /// Currently only affects WebP output, see `webp::opt::opt_fast`.
+    pub fn fast_mode(&mut self, fast_mode: bool) {
+        self.fast_mode = fast_mode;
+    }
+    pub fn is_animated(&self) -> bool {
+        self.animation.is_some()
+    }
+    /// The fields that don't depend on the output format.
+    fn out_meda(
+        &self,
+        started: Instant,
+        output: &[u8],
+        output_dimensions: (u32, u32),
+        class: Class,
+        class_meta: Meta,
+        extreme_mode: bool,
+    ) -> OutMeda {
+        let input_dimensions = match &self.animation {
+            Some(animation) => animation.dimensions(),
+            None => self.source.dimensions(),
+        };
+        OutMeda {
+            input_class: class,
+            class_meta,
+            input_path: None,
+            output_path: None,
+            input_size: self.source_size,
+            output_size: output.len(),
+            compression_ratio: self.source_size as f64 / output.len().max(1) as f64,
+            input_dimensions,
+            output_dimensions,
+            vmaf_score: None,
+            passed: true,
+            quality: None,
+            target_psnr: None,
+            palette_size: None,
+            png_mode: None,
+            probes: 0,
+            wall_time: started.elapsed().as_secs_f64(),
+            extreme_mode: Some(extreme_mode),
+        }
+    }
     pub fn run(self, extreme_mode: bool) -> Result<(Vec<u8>, OutMeda), ()> {
-        let input = match self.max_size {
+        // SETUP
+        let started = Instant::now();
+        let metric = self.custom_metric
+            .clone()
+            .unwrap_or_else(|| self.quality.build_metric());
+        let metric: Arc<dyn QualityMetric> = Arc::new(Cached::new(metric));
+        let threshold = self.quality.threshold;
+        // ANIMATED OUTPUT
+        // Other output formats only get the first frame.
+        let animated_webp = self.custom_encoder.is_none() && self.output_format == OutputFormat::Webp;
+        if let (Some(animation), true) = (&self.animation, animated_webp) {
+            let animation = match self.max_size.clone() {
+                Some(res) if (res.width, res.height) < animation.dimensions() => {
+                    animation.map_frames(|x| {
+                        x.resize(res.width, res.height, ::image::FilterType::Lanczos3)
+                    })
+                },
+                _ => animation.clone(),
+            };
+            let (out, report) = webp::opt::opt_animation(
+                &animation,
+                &self.webp_options,
+                &*metric,
+                threshold,
+            ).map_err(drop)?;
+            let mut meta = self.out_meda(
+                started,
+                &out,
+                animation.dimensions(),
+                report.class,
+                report.class_meta,
+                extreme_mode,
+            );
+            meta.vmaf_score = Some(report.score);
+            meta.passed = report.passed;
+            meta.quality = report.end_q;
+            meta.probes = report.probes;
+            return Ok((out, meta));
+        }
+        let input = match self.max_size.clone() {
             Some(res) if (res.width, res.height) < self.source.dimensions() => {
                 self.source.resize(res.width, res.height, ::image::FilterType::Lanczos3)
             },
             _ => self.source.clone(),
         };
-        match self.output_format {
-            OutputFormat::Webp => {
-                let (out, meta) = webp::opt::opt(&input);
-                let meta = OutMeda {
-                    input_class: meta.class,
-                    input_path: meta.input_path,
-                    output_path: meta.output_path,
-                    vmaf_score: None,
-                    extreme_mode: Some(extreme_mode),
-                };
-                Ok((out, meta))
-            }
-            OutputFormat::Jpeg => {
-                let (out, meta) = jpeg::OptContext::from_image(input.clone())
-                    .run_search(extreme_mode);
-                let meta = OutMeda {
-                    input_class: meta.class,
-                    input_path: None,
-                    output_path: None,
-                    vmaf_score: meta.vmaf_score,
-                    extreme_mode: Some(extreme_mode),
-                };
-                Ok((out, meta))
+        // STILL IMAGES
+        let encoder = self.custom_encoder
+            .clone()
+            .unwrap_or_else(|| self.builtin_encoder(extreme_mode));
+        let (out, report) = encoder.search(&input, metric, threshold).map_err(drop)?;
+        let mut meta = self.out_meda(
+            started,
+            &out,
+            input.dimensions(),
+            report.class,
+            report.class_meta,
+            extreme_mode,
+        );
+        meta.vmaf_score = report.score;
+        meta.passed = report.passed;
+        meta.probes = report.probes;
+        meta.target_psnr = report.target_psnr;
+        // The PNG quality parameter is the palette size.
+        match report.png_mode {
+            Some(png_mode) => {
+                meta.palette_size = report.quality.map(|x| x as usize);
+                meta.png_mode = Some(png_mode);
             }
-            OutputFormat::Png => {
-                let class_report = crate::classifier::report(&input);
-                let out = png::basic_optimize(&input);
-                let meta = OutMeda {
-                    input_class: class_report.class,
-                    input_path: None,
-                    output_path: None,
-                    vmaf_score: None,
-                    extreme_mode: Some(extreme_mode),
-                };
-                Ok((out, meta))
+            None => {
+                meta.quality = report.quality;
             }
         }
+        Ok((out, meta))
+    }
+    /// The encoder (and search) for `output_format`.
+    fn builtin_encoder(&self, extreme_mode: bool) -> Arc<dyn Encoder> {
+        match self.output_format {
+            OutputFormat::Jpeg => Arc::new(JpegEncoder {extreme_mode}),
+            OutputFormat::Png => Arc::new(PngEncoder(self.png_options.clone())),
+            OutputFormat::Webp => Arc::new(WebpEncoder {
+                options: self.webp_options.clone(),
+                fast_mode: self.fast_mode,
+            }),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// ANALYSIS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Only decodes the image the classifier looks at, i.e. the first frame of
+/// animations.
+fn decode_first_frame(source: &[u8]) -> Result<DynamicImage, ()> {
+    match ::image::guess_format(source).map_err(drop)? {
+        ImageFormat::WEBP if webp::anim::is_animated(source) => {
+            let animation = webp::anim::decode(source).map_err(drop)?;
+            Ok(animation.first_frame().clone())
+        }
+        ImageFormat::WEBP => Ok(webp::decode::decode(source)),
+        format => ::image::load_from_memory_with_format(source, format).map_err(drop),
     }
 }
 
+/// Classifies the image without optimizing it. This is what the optimizers
+/// use to pick their thresholds.
+pub fn analyze(source: &[u8]) -> Result<classifier::Report, ()> {
+    Ok(classifier::report(&decode_first_frame(source)?))
+}
+
+/// Like `analyze`, but also keeps the classifier's intermediate images,
+/// see `classifier::DebugImages::save`.
+pub fn analyze_with_debug_images(source: &[u8]) -> Result<classifier::Report, ()> {
+    Ok(classifier::report_with_debug_images(&decode_first_frame(source)?))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -133,4 +351,14 @@ mod test {
             assert!(result.is_ok());
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_analyze() {
+        let test_image = include_bytes!("../assets/test/1.jpeg");
+        let report = analyze(test_image).expect("analyze");
+        assert!(report.debug_images.is_none());
+        assert!(serde_json::to_string(&report).is_ok());
+        let report = analyze_with_debug_images(test_image).expect("analyze");
+        assert!(report.debug_images.is_some());
+    }
+}
@@ -107,13 +107,33 @@ pub fn random_color_map(keys: HashSet<u32>) -> HashMap<u32, image::Rgb<u8>> {
     output
 }
 
+/// Features are computed on images of about this many pixels. The
+/// thresholds are ratios of the analyzed area, originally tuned on 700x700.
+const ANALYSIS_AREA: u32 = 700 * 700;
+
+/// Aspect-preserving resample to about `ANALYSIS_AREA` pixels. Small images
+/// are upscaled too, since edges are a few pixels wide at any resolution,
+/// i.e. a bigger share of a smaller image.
+fn analysis_image(media: &DynamicImage) -> DynamicImage {
+    let (width, height) = media.dimensions();
+    let scale = (ANALYSIS_AREA as f64 / (width as f64 * height as f64)).sqrt();
+    let new_width = ((width as f64 * scale).round() as u32).max(1);
+    let new_height = ((height as f64 * scale).round() as u32).max(1);
+    if (new_width, new_height) == (width, height) {
+        return media.clone();
+    }
+    let filter = if scale < 1.0 {FilterType::Gaussian} else {FilterType::Triangle};
+    media.resize_exact(new_width, new_height, filter)
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 // WHITE COUNT
 ///////////////////////////////////////////////////////////////////////////////
 
 pub fn is_white_dominant(media: &DynamicImage) -> bool {
     // LOAD
-    let media = media.resize_exact(700, 700, FilterType::Gaussian);
+    let media = analysis_image(media);
+    let area = (media.width() * media.height()) as f64;
     let mut gray_media = media.to_luma();
     let mut media = image::imageops::colorops::contrast(&gray_media, 5.0);
     // SETUP
@@ -141,7 +161,7 @@ pub fn is_white_dominant(media: &DynamicImage) -> bool {
     for (x, y, px) in components.enumerate_pixels_mut() {
         let key = px.0[0];
         if let Some(val) = regions_reg.get(&key).map(|x| x.clone()) {
-            if val < 15_000 {
+            if (val as f64 / area) < 0.031 {
                 px.0[0] = 0;
             }
         }
@@ -164,7 +184,7 @@ pub fn is_white_dominant(media: &DynamicImage) -> bool {
         });
     }
     // DONE
-    sum > 130_100
+    (sum as f64 / area) > 0.266
 }
 
 
@@ -172,26 +192,54 @@ pub fn is_white_dominant(media: &DynamicImage) -> bool {
 // PROCESS
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Intermediate images, for debugging the classifier.
 #[derive(Clone)]
 pub struct DebugImages {
     pub grayscale: GrayImage,
+    pub segmentation: GrayImage,
     pub edges: GrayImage,
     pub regions: RgbImage,
 }
 
-#[derive(Clone)]
+impl DebugImages {
+    /// Saves each image as a PNG file under the given directory.
+    pub fn save<P: AsRef<std::path::Path>>(&self, dir: P) -> Result<(), String> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir).map_err(|e| format!("create {:?}: {}", dir, e))?;
+        let save = |name: &str, result: std::io::Result<()>| {
+            result.map_err(|e| format!("save {}: {}", name, e))
+        };
+        save("grayscale", self.grayscale.save(dir.join("grayscale.png")))?;
+        save("segmentation", self.segmentation.save(dir.join("segmentation.png")))?;
+        save("edges", self.edges.save(dir.join("edges.png")))?;
+        save("regions", self.regions.save(dir.join("regions.png")))?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Meta {
     pub edges_sum: usize,
     pub regions_sum: usize,
     pub component_count: usize,
     pub white_count: usize,
+    /// Pixels of the (resampled) image the features are computed on.
+    pub area: usize,
+    /// `edges_sum / area`.
+    pub edges_ratio: f64,
+    /// `regions_sum / area`, i.e. the share of the largest region.
+    pub regions_ratio: f64,
+    /// `white_count / area`.
+    pub white_ratio: f64,
 }
 
 
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Report {
-    pub debug_images: DebugImages,
+    /// Only with `report_with_debug_images`.
+    #[serde(skip)]
+    pub debug_images: Option<DebugImages>,
     pub meta: Meta,
     pub class: Class,
     pub white_backdrop: bool,
@@ -201,16 +249,16 @@ pub struct Report {
 
 fn calcuate_class(meta: &Meta) -> Class {
     let mut output = Class::L1;
-    if meta.edges_sum >= 110_000 && meta.regions_sum <= 12_000 && meta.component_count < 90 {
+    if meta.edges_ratio >= 0.224 && meta.regions_ratio <= 0.024 && meta.component_count < 90 {
         output = Class::H2;
     }
-    else if meta.edges_sum >= 70_000 && meta.regions_sum <= 12_000 && meta.component_count < 90 {
+    else if meta.edges_ratio >= 0.143 && meta.regions_ratio <= 0.024 && meta.component_count < 90 {
         output = Class::H1;
     }
-    else if meta.edges_sum >= 60_000 && meta.regions_sum <= 90_000 {
+    else if meta.edges_ratio >= 0.122 && meta.regions_ratio <= 0.184 {
         output = Class::M1;
     }
-    else if meta.edges_sum >= 20_000 && meta.regions_sum <= 200_000 {
+    else if meta.edges_ratio >= 0.041 && meta.regions_ratio <= 0.408 {
         output = Class::L2;
     }
     else if meta.component_count > 20 {
@@ -222,16 +270,29 @@ fn calcuate_class(meta: &Meta) -> Class {
     output
 }
 
+/// Splits the image into dark (`0`) and light (`255`) regions, using Otsu's
+/// threshold.
 pub fn grayscale_segmentation(media: &DynamicImage) -> GrayImage {
-    let mut grayscale_media = media.to_luma();
-    unimplemented!()
+    let grayscale_media = media.to_luma();
+    let level = imageproc::contrast::otsu_level(&grayscale_media);
+    imageproc::contrast::threshold(&grayscale_media, level)
 }
 
 pub fn report(media: &DynamicImage) -> Report {
+    report_impl(media, false)
+}
+
+/// Like `report`, but also keeps the intermediate images.
+pub fn report_with_debug_images(media: &DynamicImage) -> Report {
+    report_impl(media, true)
+}
+
+fn report_impl(media: &DynamicImage, with_debug_images: bool) -> Report {
     // MISC
     let white_dominant = is_white_dominant(&media);
     // PRE-PROCESS IMAGE
-    let media = media.resize_exact(700, 700, FilterType::Gaussian);
+    let media = analysis_image(media);
+    let area = (media.width() * media.height()) as usize;
     // DOMINANT COLORS
     let mut grayscale_media = media.to_luma();
     for (_, _, px) in grayscale_media.enumerate_pixels_mut() {
@@ -284,17 +345,22 @@ pub fn report(media: &DynamicImage) -> Report {
         }
     }
     let regions_sum = region_sums.values().map(|x| x).max().map(|x| x.clone()).unwrap_or(0);
-    let debug_colors = random_color_map(components.pixels().map(|p| p[0]).map(|x| x).collect());
-    let regions_media = ImageBuffer::from_fn(regions_media.width(), regions_media.height(), |x, y| {
-        let px_key = components.get_pixel(x, y).channels()[0];
-        let color = debug_colors.get(&px_key).expect("missing color entry");
-        color.clone()
-    });
     // DEBUG IMAGES
-    let debug_images = DebugImages {
-        grayscale: grayscale_media,
-        edges: edges_media,
-        regions: regions_media,
+    let debug_images = if with_debug_images {
+        let debug_colors = random_color_map(components.pixels().map(|p| p[0]).map(|x| x).collect());
+        let regions_media = ImageBuffer::from_fn(regions_media.width(), regions_media.height(), |x, y| {
+            let px_key = components.get_pixel(x, y).channels()[0];
+            let color = debug_colors.get(&px_key).expect("missing color entry");
+            color.clone()
+        });
+        Some(DebugImages {
+            grayscale: grayscale_media,
+            segmentation: grayscale_segmentation(&media),
+            edges: edges_media,
+            regions: regions_media,
+        })
+    } else {
+        None
     };
     // META
     let meta = Meta {
@@ -302,6 +368,10 @@ pub fn report(media: &DynamicImage) -> Report {
         regions_sum,
         component_count: component_count as usize,
         white_count: white_count,
+        area,
+        edges_ratio: edges_sum as f64 / area as f64,
+        regions_ratio: regions_sum as f64 / area as f64,
+        white_ratio: white_count as f64 / area as f64,
     };
     // CLASS
     let class = calcuate_class(&meta);
@@ -313,3 +383,57 @@ pub fn report(media: &DynamicImage) -> Report {
         white_backdrop: white_dominant,
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    /// An entry of `assets/test/classes.json`.
+    #[derive(Deserialize)]
+    struct Label {
+        file: String,
+        class: Class,
+        white_backdrop: bool,
+    }
+
+    #[test]
+    fn test_labeled_set() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/test");
+        let labels = std::fs::read(dir.join("classes.json")).expect("read classes.json");
+        let labels: Vec<Label> = serde_json::from_slice(&labels).expect("parse classes.json");
+        for label in labels {
+            let media = ::image::open(dir.join(&label.file)).expect("open labeled image");
+            let report = report(&media);
+            assert!(report.class == label.class, "{}: got {}, expected {}", label.file, report.class, label.class);
+            assert!(report.white_backdrop == label.white_backdrop, "{}: white backdrop", label.file);
+        }
+    }
+
+    #[test]
+    fn test_resolution_independent() {
+        let media = ::image::load_from_memory(include_bytes!("../assets/test/1.jpeg"))
+            .expect("load test image");
+        let (width, height) = media.dimensions();
+        let larger = media.resize_exact(width * 2, height * 2, FilterType::Triangle);
+        assert!(report(&media).class == report(&larger).class);
+    }
+
+    #[test]
+    fn test_small_image_upscaled() {
+        let media = ::image::load_from_memory(include_bytes!("../assets/test/1.jpeg"))
+            .expect("load test image")
+            .resize(300, 300, FilterType::Triangle);
+        let (width, height) = media.dimensions();
+        assert!(width * height < 700 * 700);
+        let small = report(&media);
+        for factor in &[2, 4] {
+            let larger = media.resize_exact(width * factor, height * factor, FilterType::Triangle);
+            let larger = report(&larger);
+            assert!(small.class == larger.class, "{}x: got {}, expected {}", factor, larger.class, small.class);
+            let ratio = small.meta.edges_ratio / larger.meta.edges_ratio;
+            assert!((ratio - 1.0).abs() < 0.2, "{}x: edges ratio off by {}", factor, ratio);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//! A common interface for (lossy) encoders, and a search that works with
+//! any of them.
+use std::sync::Arc;
+use image::{DynamicImage, GenericImageView, ImageFormat};
+use serde::{Serialize, Deserialize};
+
+use crate::data::VideoBuffer;
+use crate::classifier::{self, Class};
+use crate::metrics::{self, QualityMetric, Score};
+use crate::codec::{jpeg, png, webp};
+use crate::codec::png::PngOptions;
+use crate::codec::webp::encode::lossy::WebpOptions;
+
+
+///////////////////////////////////////////////////////////////////////////////
+// INTERFACE
+///////////////////////////////////////////////////////////////////////////////
+
+/// Inclusive range of the quality parameter, higher means better quality
+/// (and bigger files).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QualityRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+pub trait Encoder: Send + Sync {
+    fn name(&self) -> &str;
+    fn quality_range(&self) -> QualityRange;
+    fn encode(&self, source: &DynamicImage, quality: u32) -> Result<Vec<u8>, String>;
+    /// Decodes the output of `encode`, for scoring.
+    fn decode(&self, encoded: &[u8]) -> Result<DynamicImage, String>;
+    /// The (VMAF) threshold for the given image class, JPEG's by default.
+    fn class_threshold(&self, class_report: &classifier::Report, dimensions: (u32, u32)) -> f64 {
+        class_threshold(class_report, dimensions, false)
+    }
+    /// The quality search, `optimize` unless the encoder has its own. Takes
+    /// an `Arc` since some searches (`jpeg::OptContext`) keep the metric.
+    fn search(
+        &self,
+        source: &DynamicImage,
+        metric: Arc<dyn QualityMetric>,
+        threshold: Option<f64>,
+    ) -> Result<(Vec<u8>, SearchReport), String> {
+        optimize(self, source, &*metric, threshold)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// THRESHOLDS
+///////////////////////////////////////////////////////////////////////////////
+
+/// The JPEG VMAF threshold for an image class, also the default
+/// `Encoder::class_threshold`. Only used when neither the caller nor the
+/// metric has a threshold, see `metrics::resolve_threshold`.
+///
+/// `extreme_mode` lowers the bar for big text and line-art images.
+pub fn class_threshold(
+    class_report: &classifier::Report,
+    dimensions: (u32, u32),
+    extreme_mode: bool,
+) -> f64 {
+    let (width, height) = dimensions;
+    let is_small = (width * height) <= (500 * 500);
+    let is_big = (width * height) >= (1200 * 1000);
+    match class_report.class {
+        Class::L0 if class_report.white_backdrop => 96.0,
+        Class::L1 if class_report.white_backdrop => 94.0,
+        Class::L2 if class_report.white_backdrop => 93.0,
+        Class::L0 | Class::L1 | Class::L2 if extreme_mode && is_big => 95.0,
+        Class::L0 => 99.0,
+        Class::L1 => 98.0,
+        Class::L2 => 96.0,
+        Class::M1 => 92.0,
+        Class::H1 | Class::H2 if is_small => 88.0,
+        Class::H1 => 84.0,
+        Class::H2 => 76.0,
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// BUILT-IN ENCODERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Searches with `jpeg::OptContext`.
+#[derive(Debug, Clone, Default)]
+pub struct JpegEncoder {
+    /// See `class_threshold`.
+    pub extreme_mode: bool,
+}
+
+impl Encoder for JpegEncoder {
+    fn name(&self) -> &str {
+        "jpeg"
+    }
+    fn quality_range(&self) -> QualityRange {
+        QualityRange {min: 0, max: 100}
+    }
+    fn encode(&self, source: &DynamicImage, quality: u32) -> Result<Vec<u8>, String> {
+        Ok(unsafe {jpeg::encode(source, quality.min(100) as u8)})
+    }
+    fn decode(&self, encoded: &[u8]) -> Result<DynamicImage, String> {
+        ::image::load_from_memory_with_format(encoded, ImageFormat::JPEG)
+            .map_err(|e| format!("decode jpeg: {}", e))
+    }
+    fn class_threshold(&self, class_report: &classifier::Report, dimensions: (u32, u32)) -> f64 {
+        class_threshold(class_report, dimensions, self.extreme_mode)
+    }
+    fn search(
+        &self,
+        source: &DynamicImage,
+        metric: Arc<dyn QualityMetric>,
+        threshold: Option<f64>,
+    ) -> Result<(Vec<u8>, SearchReport), String> {
+        let mut opt_ctx = jpeg::OptContext::from_image(source.clone());
+        opt_ctx.metric(metric);
+        if let Some(threshold) = threshold {
+            opt_ctx.threshold(threshold);
+        }
+        let (encoded, report) = opt_ctx.run_search(self.extreme_mode);
+        let report = SearchReport {
+            encoder: self.name().to_owned(),
+            class: report.class,
+            class_meta: report.class_meta,
+            quality: Some(report.end_q as u32),
+            target_psnr: None,
+            score: report.vmaf_score,
+            passed: report.passed,
+            probes: report.probes,
+            png_mode: None,
+        };
+        Ok((encoded, report))
+    }
+}
+
+/// Searches with `webp::opt::opt`, or `webp::opt::opt_fast` in fast mode.
+#[derive(Debug, Clone, Default)]
+pub struct WebpEncoder {
+    pub options: WebpOptions,
+    pub fast_mode: bool,
+}
+
+impl Encoder for WebpEncoder {
+    fn name(&self) -> &str {
+        "webp"
+    }
+    fn quality_range(&self) -> QualityRange {
+        QualityRange {min: 0, max: 100}
+    }
+    fn encode(&self, source: &DynamicImage, quality: u32) -> Result<Vec<u8>, String> {
+        Ok(webp::encode::lossy::encode(source, quality as f32, &self.options))
+    }
+    fn decode(&self, encoded: &[u8]) -> Result<DynamicImage, String> {
+        Ok(webp::decode::decode(encoded))
+    }
+    fn class_threshold(&self, class_report: &classifier::Report, dimensions: (u32, u32)) -> f64 {
+        webp::opt::class_threshold(&class_report.class, dimensions)
+    }
+    fn search(
+        &self,
+        source: &DynamicImage,
+        metric: Arc<dyn QualityMetric>,
+        threshold: Option<f64>,
+    ) -> Result<(Vec<u8>, SearchReport), String> {
+        let (encoded, meta) = if self.fast_mode {
+            webp::opt::opt_fast(source, &self.options, &*metric, threshold)
+        } else {
+            webp::opt::opt(source, &self.options, &*metric, threshold)
+        };
+        let report = SearchReport {
+            encoder: self.name().to_owned(),
+            class: meta.class,
+            class_meta: meta.class_meta,
+            quality: meta.end_q,
+            target_psnr: meta.target_psnr,
+            score: Some(meta.score),
+            passed: meta.passed,
+            probes: meta.probes,
+            png_mode: None,
+        };
+        Ok((encoded, report))
+    }
+}
+
+/// Palette PNGs, the quality parameter is the number of colors.
+///
+/// Searches with `png::basic_optimize`, which also tries a lossless
+/// re-encode and checks the alpha channel.
+#[derive(Debug, Clone, Default)]
+pub struct PngEncoder(pub PngOptions);
+
+impl Encoder for PngEncoder {
+    fn name(&self) -> &str {
+        "png"
+    }
+    fn quality_range(&self) -> QualityRange {
+        QualityRange {min: 1, max: 256}
+    }
+    fn encode(&self, source: &DynamicImage, quality: u32) -> Result<Vec<u8>, String> {
+        let mode = self.0.mode.clone().unwrap_or(png::ImageMode::Text);
+        png::compress(source, mode, quality as usize)
+    }
+    fn decode(&self, encoded: &[u8]) -> Result<DynamicImage, String> {
+        ::image::load_from_memory_with_format(encoded, ImageFormat::PNG)
+            .map_err(|e| format!("decode png: {}", e))
+    }
+    fn class_threshold(&self, _: &classifier::Report, _: (u32, u32)) -> f64 {
+        png::THRESHOLD
+    }
+    fn search(
+        &self,
+        source: &DynamicImage,
+        metric: Arc<dyn QualityMetric>,
+        threshold: Option<f64>,
+    ) -> Result<(Vec<u8>, SearchReport), String> {
+        let (encoded, report) = png::basic_optimize(source, &*metric, threshold, &self.0);
+        let report = SearchReport {
+            encoder: self.name().to_owned(),
+            class: report.class,
+            class_meta: report.class_meta,
+            quality: report.num_colors.map(|x| x as u32),
+            target_psnr: None,
+            score: report.score,
+            // The lossless fallback always passes.
+            passed: true,
+            probes: report.probes,
+            png_mode: Some(report.mode),
+        };
+        Ok((encoded, report))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// SEARCH
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchReport {
+    pub encoder: String,
+    pub class: Class,
+    pub class_meta: crate::classifier::Meta,
+    /// `None` for lossless output, and when the encoder's own rate control
+    /// picked the quality.
+    pub quality: Option<u32>,
+    /// The PSNR the encoder's rate control targeted, WebP fast mode only.
+    pub target_psnr: Option<f64>,
+    /// `None` when the output wasn't scored, e.g. lossless PNGs.
+    pub score: Option<f64>,
+    pub passed: bool,
+    /// Number of encodes that were scored.
+    pub probes: usize,
+    /// PNG only.
+    pub png_mode: Option<png::ImageMode>,
+}
+
+/// Bisects over the encoder's quality range for the lowest quality that
+/// passes, assuming scores only improve with quality. If nothing passes,
+/// the result is encoded at the highest quality.
+pub fn optimize<E: Encoder + ?Sized>(
+    encoder: &E,
+    source: &DynamicImage,
+    metric: &dyn QualityMetric,
+    threshold: Option<f64>,
+) -> Result<(Vec<u8>, SearchReport), String> {
+    // SETUP
+    let class_report = crate::classifier::report(source);
+    let threshold = metrics::resolve_threshold(
+        metric,
+        threshold,
+        encoder.class_threshold(&class_report, source.dimensions()),
+    );
+    let reference = VideoBuffer::from_image(source)
+        .map_err(|_| String::from("source to VideoBuffer"))?;
+    let mut probes = 0;
+    let mut run = |quality: u32| -> Result<(Vec<u8>, Score), String> {
+        let encoded = encoder.encode(source, quality)?;
+        let decoded = encoder.decode(&encoded)?;
+        let decoded = VideoBuffer::from_image(&decoded)
+            .map_err(|_| String::from("derivative to VideoBuffer"))?;
+        probes = probes + 1;
+        Ok((encoded, metric.score(&reference, &decoded)))
+    };
+    // GO!
+    let range = encoder.quality_range();
+    let (mut low, mut high) = (range.min, range.max);
+    let mut passed: Option<(Vec<u8>, u32, Score)> = None;
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let (encoded, score) = run(quality)?;
+        if score.passes(threshold) {
+            passed = Some((encoded, quality, score));
+            if quality == range.min {
+                break;
+            }
+            high = quality - 1;
+        } else {
+            low = quality + 1;
+        }
+    }
+    // DONE
+    let (encoded, quality, score, passed) = match passed {
+        Some((encoded, quality, score)) => (encoded, quality, score, true),
+        None => {
+            let (encoded, score) = run(range.max)?;
+            (encoded, range.max, score, false)
+        }
+    };
+    let report = SearchReport {
+        encoder: encoder.name().to_owned(),
+        class: class_report.class,
+        class_meta: class_report.meta,
+        quality: Some(quality),
+        target_psnr: None,
+        score: Some(score.value),
+        passed,
+        probes,
+        png_mode: None,
+    };
+    Ok((encoded, report))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::FnMetric;
+    use crate::testing::test_image;
+
+    /// Encodes just the quality, which decodes to an image `2 * (quality + 1)`
+    /// pixels wide. So a metric can read the quality back off the width.
+    struct StepEncoder;
+
+    impl Encoder for StepEncoder {
+        fn name(&self) -> &str {
+            "step"
+        }
+        fn quality_range(&self) -> QualityRange {
+            QualityRange {min: 0, max: 100}
+        }
+        fn encode(&self, _: &DynamicImage, quality: u32) -> Result<Vec<u8>, String> {
+            Ok(vec![quality as u8])
+        }
+        fn decode(&self, encoded: &[u8]) -> Result<DynamicImage, String> {
+            let width = 2 * (encoded[0] as u32 + 1);
+            Ok(DynamicImage::ImageLuma8(::image::GrayImage::new(width, 2)))
+        }
+    }
+
+    #[test]
+    fn test_optimize_finds_boundary() {
+        let source = test_image();
+        let metric = FnMetric(|_: &VideoBuffer, x: &VideoBuffer| (x.width() / 2 - 1) as f64);
+        // 50, 24, 37, 30, 33, 35, 36
+        let (encoded, report) = optimize(&StepEncoder, &source, &metric, Some(37.0)).expect("optimize");
+        assert_eq!(encoded, vec![37]);
+        assert!(report.passed && report.quality == Some(37));
+        assert_eq!(report.score, Some(37.0));
+        assert_eq!(report.probes, 7);
+        // 50, 75, 88, 94, 97, 99, 100 AND THE FALLBACK
+        let (encoded, report) = optimize(&StepEncoder, &source, &metric, Some(101.0)).expect("optimize");
+        assert_eq!(encoded, vec![100]);
+        assert!(!report.passed && report.quality == Some(100));
+        assert_eq!(report.probes, 8);
+    }
+}
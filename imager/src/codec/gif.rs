@@ -0,0 +1,77 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use std::io::Cursor;
+use image::{DynamicImage, AnimationDecoder};
+
+use crate::data::{Animation, AnimationFrame};
+
+/// Decode all frames, composited onto the full canvas.
+pub fn decode(source: &[u8]) -> Result<Animation, String> {
+    let decoder = ::image::gif::Decoder::new(Cursor::new(source))
+        .map_err(|e| format!("gif decoder: {}", e))?;
+    let frames = decoder
+        .into_frames()
+        .collect_frames()
+        .map_err(|e| format!("gif frames: {}", e))?
+        .into_iter()
+        .map(|frame| {
+            let delay = crate::shared::gif_delay(frame.delay().to_integer() as u32);
+            AnimationFrame {
+                image: DynamicImage::ImageRgba8(frame.into_buffer()),
+                delay,
+            }
+        })
+        .collect::<Vec<_>>();
+    if frames.is_empty() {
+        return Err(String::from("gif without frames"));
+    }
+    Ok(Animation {
+        frames,
+        loop_count: loop_count(source)?,
+    })
+}
+
+/// The NETSCAPE2.0 (or ANIMEXTS1.0) loop count, in WebP terms: the total
+/// number of plays, where `0` means forever. Without the extension a GIF
+/// plays once.
+fn loop_count(source: &[u8]) -> Result<u32, String> {
+    let mut decoder = ::gif::StreamingDecoder::new();
+    let mut position = 0;
+    // ONE BYTE AT A TIME
+    // gif 0.10 only saves the first byte of every chunk it's given while
+    // reading extension blocks.
+    while position < source.len() {
+        let (consumed, decoded) = decoder
+            .update(&source[position..position + 1])
+            .map_err(|e| format!("gif extensions: {}", e))?;
+        if consumed == 0 {
+            break;
+        }
+        position = position + consumed;
+        match decoded {
+            ::gif::Decoded::BlockFinished(0xFF, data) => {
+                if let Some(repeat) = netscape_repeat(data) {
+                    // GIF COUNTS THE REPEATS AFTER THE FIRST PLAY
+                    return Ok(if repeat == 0 {0} else {repeat as u32 + 1});
+                }
+            }
+            // THE EXTENSION COMES BEFORE THE FIRST FRAME
+            ::gif::Decoded::Frame(_) | ::gif::Decoded::Trailer => break,
+            _ => (),
+        }
+    }
+    Ok(1)
+}
+
+/// The repeat count from the application extension data, i.e. the
+/// identifier followed by the `[1, lo, hi]` sub-block.
+fn netscape_repeat(data: &[u8]) -> Option<u16> {
+    let index = data
+        .windows(11)
+        .position(|x| x == b"NETSCAPE2.0" || x == b"ANIMEXTS1.0")?;
+    match data.get(index + 11..index + 14)? {
+        [1, lo, hi] => Some(*lo as u16 | (*hi as u16) << 8),
+        _ => None,
+    }
+}
@@ -2,6 +2,8 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::convert::From;
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int};
@@ -21,6 +23,7 @@ use itertools::Itertools;
 use crate::data::{VideoBuffer, Yuv420P};
 use crate::classifier::{self, Class};
 use crate::vmaf;
+use crate::metrics::{self, QualityMetric, Score, Vmaf};
 
 ///////////////////////////////////////////////////////////////////////////////
 // MOZJPEG FFI HELPERS
@@ -123,7 +126,10 @@ pub struct OptReport {
     pub end_q: u8,
     pub passed: bool,
     pub class: Class,
+    pub class_meta: classifier::Meta,
     pub vmaf_score: Option<f64>,
+    /// Number of encodes that were scored.
+    pub probes: usize,
 }
 
 pub struct OptContext {
@@ -131,6 +137,9 @@ pub struct OptContext {
     vmaf_source: VideoBuffer,
     class_report: classifier::Report,
     extreme_mode: bool,
+    metric: Arc<dyn QualityMetric>,
+    threshold: Option<f64>,
+    probes: AtomicUsize,
 }
 
 impl OptContext {
@@ -140,58 +149,26 @@ impl OptContext {
             class_report: classifier::report(&source),
             source: source,
             extreme_mode: false,
+            metric: Arc::new(Vmaf(vmaf::VmafOptions::default())),
+            threshold: None,
+            probes: AtomicUsize::new(0),
         }
     }
-    fn terminate(&self, score: f64) -> bool {
-        let mut threshold;
-        let (width, height) = self.source.dimensions();
-        let is_small = {
-            (width * height) <= (500 * 500)
-        };
-        let is_big = {
-            (width * height) >= (1200 * 1000)
-        };
-        match self.class_report.class {
-            Class::L0 if self.class_report.white_backdrop => {
-                threshold = 96.0;
-            }
-            Class::L1 if self.class_report.white_backdrop => {
-                threshold = 94.0;
-            }
-            Class::L2 if self.class_report.white_backdrop => {
-                threshold = 93.0;
-            }
-            Class::L0 | Class::L1 | Class::L2 if self.extreme_mode && is_big => {
-                threshold = 95.0;
-            }
-            Class::L0 => {
-                threshold = 99.0;
-            }
-            Class::L1 => {
-                threshold = 98.0;
-            }
-            Class::L2 => {
-                threshold = 96.0;
-            }
-            Class::M1 => {
-                threshold = 92.0;
-            }
-            Class::H1 if !is_small => {
-                threshold = 84.0;
-            }
-            Class::H2 if !is_small => {
-                threshold = 76.0;
-            }
-            Class::H1 | Class::H2 => {
-                assert!(is_small);
-                threshold = 88.0;
-            }
-        }
-        if (score >= threshold) {
-            true
-        } else {
-            false
-        }
+    pub fn metric(&mut self, metric: Arc<dyn QualityMetric>) {
+        self.metric = metric;
+    }
+    /// Overrides the per-class thresholds.
+    pub fn threshold(&mut self, threshold: f64) {
+        self.threshold = Some(threshold);
+    }
+    fn terminate(&self, score: Score) -> bool {
+        let threshold = crate::codec::encoder::class_threshold(
+            &self.class_report,
+            self.source.dimensions(),
+            self.extreme_mode,
+        );
+        let threshold = metrics::resolve_threshold(&*self.metric, self.threshold, threshold);
+        score.passes(threshold)
     }
     fn find_starting_position(&self) -> Option<u8> {
         let reduce_starting_values = |qs: Vec<u8>| -> Option<u8> {
@@ -252,18 +229,19 @@ impl OptContext {
         }
     }
     fn run_instance(&self, q: u8) -> (Vec<u8>, bool, f64) {
+        self.probes.fetch_add(1, Ordering::SeqCst);
         let compressed = unsafe {
             encode(&self.source, q)
         };
         // TODO - CLEANUP
-        let report: f64 = {
+        let report: Score = {
             let vmaf_derivative = VideoBuffer::from_jpeg(&compressed).expect("load jpeg image");
-            vmaf::get_report(&self.vmaf_source, &vmaf_derivative)
+            self.metric.score(&self.vmaf_source, &vmaf_derivative)
         };
         if self.terminate(report) {
-            (compressed, true, report)
+            (compressed, true, report.value)
         } else {
-            (compressed, false, report)
+            (compressed, false, report.value)
         }
     }
     pub fn run_search(&mut self, extreme_mode: bool) -> (Vec<u8>, OptReport) {
@@ -278,7 +256,9 @@ impl OptContext {
                     end_q: q,
                     passed: true,
                     class: self.class_report.class.clone(),
+                    class_meta: self.class_report.meta.clone(),
                     vmaf_score: Some(score),
+                    probes: self.probes.load(Ordering::SeqCst),
                 };
                 passed_output = Some((compressed, out_meta));
                 break;
@@ -296,7 +276,9 @@ impl OptContext {
                     end_q: fallback_q,
                     passed: false,
                     class: self.class_report.class.clone(),
+                    class_meta: self.class_report.meta.clone(),
                     vmaf_score: None,
+                    probes: self.probes.load(Ordering::SeqCst),
                 };
                 (payload, out_meta)
             }
@@ -313,7 +295,9 @@ impl OptContext {
                         end_q: fallback_q,
                         passed: false,
                         class: self.class_report.class.clone(),
+                        class_meta: self.class_report.meta.clone(),
                         vmaf_score: None,
+                        probes: self.probes.load(Ordering::SeqCst),
                     };
                     (payload, out_meta)
                 }
@@ -1,3 +1,5 @@
+pub mod gif;
 pub mod jpeg;
 pub mod png;
-pub mod webp;
\ No newline at end of file
+pub mod webp;
+pub mod encoder;
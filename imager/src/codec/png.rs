@@ -9,24 +9,95 @@ use exoquant::*;
 use lodepng::Bitmap;
 use lodepng::RGBA;
 use image::{DynamicImage, GenericImage, GenericImageView};
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
 
+use crate::classifier::Class;
 use crate::data::{VideoBuffer, Yuv420P};
 use crate::vmaf;
+use crate::metrics::{self, QualityMetric, Vmaf};
 
 
 ///////////////////////////////////////////////////////////////////////////////
 // DATA TYPES
 ///////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ImageMode {
+    /// Flat colors and sharp edges, no dithering.
     Text,
+    /// Illustrations and UI graphics, dithered but without the noise
+    /// shaping meant for photos.
+    Graphic,
+    /// Smooth gradients, Floyd–Steinberg dithering.
+    Photo,
+}
+
+impl ImageMode {
+    pub fn from_class(class: &Class) -> Self {
+        match class {
+            Class::L0 | Class::L1 | Class::L2 => ImageMode::Text,
+            Class::M1 => ImageMode::Graphic,
+            Class::H1 | Class::H2 => ImageMode::Photo,
+        }
+    }
+    fn quantizer_config(&self) -> (Box<dyn ditherer::Ditherer>, Box<dyn Optimizer>) {
+        match self {
+            ImageMode::Text => {
+                // VALUES
+                let d = ditherer::None;
+                let o = optimizer::WeightedKMeans;
+                // DONE
+                (Box::new(d), Box::new(o))
+            }
+            ImageMode::Graphic => {
+                // VALUES
+                let d = ditherer::FloydSteinberg::vanilla();
+                let o = optimizer::WeightedKMeans;
+                // DONE
+                (Box::new(d), Box::new(o))
+            }
+            ImageMode::Photo => {
+                // VALUES
+                let d = ditherer::FloydSteinberg::new();
+                let o = optimizer::KMeans;
+                // DONE
+                (Box::new(d), Box::new(o))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PngOptions {
+    /// Skip the palette search, only re-encode losslessly.
+    pub lossless_only: bool,
+    /// Inferred from the image class when unset.
+    pub mode: Option<ImageMode>,
+    /// Palettes also have to keep the `alpha_error`, from `0` to `1`, at or
+    /// below this. VMAF doesn't see the alpha channel.
+    pub max_alpha_error: f64,
+}
+
+impl Default for PngOptions {
+    fn default() -> Self {
+        PngOptions {
+            lossless_only: false,
+            mode: None,
+            max_alpha_error: 0.01,
+        }
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 // ENCODER
 ///////////////////////////////////////////////////////////////////////////////
 
+/// lodepng only writes the `tRNS` entries up to the last translucent color,
+/// so with a sorted palette (see `sort_palette`) the chunk is as short as
+/// possible, and omitted for opaque palettes.
 fn encode_indexed(palette: &[Color], image: &[u8], width: u32, height: u32) -> Vec<u8> {
     let mut state = lodepng::State::new();
     for color in palette {
@@ -54,79 +125,299 @@ fn encode_indexed(palette: &[Color], image: &[u8], width: u32, height: u32) -> V
     state.encode(image, width as usize, height as usize).expect("encode png data")
 }
 
-pub fn compress(source: &DynamicImage, mode: ImageMode, num_colors: usize) -> Result<Vec<u8>, String> {
-    // CHECKS
-    assert!(num_colors <= 256);
-    // SETUP
-    let (ditherer, optimizer) = match mode {
-        ImageMode::Text => {
-            // VALUES
-            let d = ditherer::None;
-            let o = optimizer::WeightedKMeans;
-            // DONE
-            let d: Box<dyn ditherer::Ditherer> = Box::new(d);
-            let o: Box<dyn Optimizer> = Box::new(o);
-            (d, o)
+/// Lossless re-encode, the smallest of all filter strategies.
+///
+/// lodepng's auto conversion picks the smallest lossless color type, i.e.
+/// grey, RGB or an exact palette, reduces the bit depth where possible and
+/// drops the alpha channel when the image is opaque.
+pub fn encode_lossless(source: &DynamicImage) -> Vec<u8> {
+    let (width, height) = source.dimensions();
+    let raw = source.to_rgba().into_raw();
+    let strategies = vec![
+        lodepng::FilterStrategy::ZERO,
+        lodepng::FilterStrategy::MINSUM,
+        lodepng::FilterStrategy::ENTROPY,
+        lodepng::FilterStrategy::BRUTE_FORCE,
+    ];
+    strategies
+        .into_par_iter()
+        .map(|strategy| {
+            let mut state = lodepng::State::new();
+            state.info_raw_mut().colortype = lodepng::ColorType::RGBA;
+            state.info_raw_mut().set_bitdepth(8);
+            state.set_auto_convert(true);
+            state.set_filter_strategy(strategy, false);
+            // MAXIMUM DEFLATE EFFORT
+            state.encoder.zlibsettings.windowsize = 32768;
+            state.encoder.zlibsettings.nicematch = 258;
+            state.encoder.zlibsettings.lazymatching = 1;
+            state.encode(&raw, width as usize, height as usize).expect("encode png data")
+        })
+        .min_by_key(|x| x.len())
+        .expect("at least one filter strategy")
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// ALPHA
+///////////////////////////////////////////////////////////////////////////////
+
+fn premultiply(color: Color) -> Color {
+    let a = color.a as u32;
+    let channel = |x: u8| ((x as u32 * a + 127) / 255) as u8;
+    Color::new(channel(color.r), channel(color.g), channel(color.b), color.a)
+}
+
+fn unpremultiply(color: Color) -> Color {
+    if color.a == 0 {
+        return Color::new(0, 0, 0, 0);
+    }
+    let a = color.a as u32;
+    let channel = |x: u8| ((x as u32 * 255 + a / 2) / a).min(255) as u8;
+    Color::new(channel(color.r), channel(color.g), channel(color.b), color.a)
+}
+
+/// Composites the image over black, i.e. what's visible of it. Used for
+/// scoring, since the YUV conversion drops the alpha channel and would
+/// otherwise compare colors hidden under transparent pixels.
+fn flatten(source: &DynamicImage) -> DynamicImage {
+    let mut image = source.to_rgba();
+    for px in image.pixels_mut() {
+        let color = premultiply(Color::new(px.0[0], px.0[1], px.0[2], px.0[3]));
+        px.0 = [color.r, color.g, color.b, 255];
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+/// Mean absolute alpha difference, from `0` to `1`, over the pixels that are
+/// translucent in the source or whose alpha changed. Opaque and transparent
+/// areas that come through unchanged would otherwise dilute the error of
+/// the (usually few) anti-aliased edges.
+pub fn alpha_error(source: &DynamicImage, derivative: &DynamicImage) -> f64 {
+    assert!(source.dimensions() == derivative.dimensions());
+    let (mut total, mut count) = (0u64, 0u64);
+    for ((_, _, a), (_, _, b)) in source.pixels().zip(derivative.pixels()) {
+        let (a, b) = (a.0[3], b.0[3]);
+        let translucent = a > 0 && a < 255;
+        if translucent || a != b {
+            total = total + (a as i64 - b as i64).abs() as u64;
+            count = count + 1;
         }
-    };
-    let input_pixels = source
-        .pixels()
-        .map(|(_, _, px)| Color::new(px.0[0], px.0[1], px.0[2], px.0[3]))
-        .collect::<Vec<Color>>();
-    let histogram = Histogram::from_iter(input_pixels.clone());
-    let dev = false;
-    let colorspace = SimpleColorSpace::default();
-    let mut quantizer = Quantizer::new(&histogram, &colorspace);
-    for _ in 0..num_colors {
-        quantizer.step();
-        quantizer = quantizer.optimize(&*optimizer, 16);
     }
+    total as f64 / (255.0 * count.max(1) as f64)
+}
+
+fn has_alpha(source: &DynamicImage) -> bool {
+    source.pixels().any(|(_, _, px)| px.0[3] < 255)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// PALETTE
+///////////////////////////////////////////////////////////////////////////////
+
+/// Premultiplied, so transparent pixels collapse into a single color and
+/// semi-transparent edges are weighted by their coverage, instead of
+/// pulling in the colors hidden underneath them.
+fn input_pixels(source: &DynamicImage) -> Vec<Color> {
+    source
+        .pixels()
+        .map(|(_, _, px)| premultiply(Color::new(px.0[0], px.0[1], px.0[2], px.0[3])))
+        .collect::<Vec<Color>>()
+}
+
+/// The quantizer can't grow a palette past the number of distinct colors.
+fn distinct_colors(pixels: &[Color]) -> usize {
+    pixels
+        .iter()
+        .map(|c| (c.r, c.g, c.b, c.a))
+        .collect::<std::collections::HashSet<_>>()
+        .len()
+}
+
+/// Palette order, translucent colors first (by alpha), which keeps the
+/// `tRNS` chunk minimal, then opaque colors by luma, so neighboring indices
+/// are similar colors, which helps the PNG filters.
+fn sort_palette(palette: &[Color]) -> Vec<usize> {
+    let luma = |c: &Color| 299 * c.r as u32 + 587 * c.g as u32 + 114 * c.b as u32;
+    let mut order = (0..palette.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&ix| {
+        let color = &palette[ix];
+        (color.a == 255, color.a, luma(color))
+    });
+    order
+}
+
+/// Final palette refinement, remapping and encoding for one palette size.
+fn encode_palette(
+    source: &DynamicImage,
+    mode: &ImageMode,
+    input_pixels: &[Color],
+    histogram: &Histogram,
+    colorspace: &SimpleColorSpace,
+    palette: &[Color],
+) -> Vec<u8> {
+    let (ditherer, optimizer) = mode.quantizer_config();
     // PALETTE DATA
-    let palette = quantizer.colors(&colorspace);
-    let palette = optimizer.optimize_palette(&colorspace, &palette, &histogram, 16);
-    let remapper = Remapper::new(&palette, &colorspace, &*ditherer);
+    let palette = optimizer.optimize_palette(colorspace, palette, histogram, 16);
+    let remapper = Remapper::new(&palette, colorspace, &*ditherer);
     // PIXEL DATA
     let out_data: Vec<u8> = remapper
-        .remap_iter(Box::new(input_pixels.into_iter()), source.width() as usize)
+        .remap_iter(Box::new(input_pixels.to_vec().into_iter()), source.width() as usize)
         .collect();
+    // SORT
+    let order = sort_palette(&palette);
+    let mut new_index = vec![0u8; palette.len()];
+    for (new, &old) in order.iter().enumerate() {
+        new_index[old] = new as u8;
+    }
+    let palette = order
+        .iter()
+        .map(|&ix| unpremultiply(palette[ix]))
+        .collect::<Vec<_>>();
+    let out_data = out_data
+        .into_iter()
+        .map(|ix| new_index[ix as usize])
+        .collect::<Vec<_>>();
     // ENCODE
-    let out_file = encode_indexed(
+    encode_indexed(
         &palette,
         &out_data,
         source.width(),
         source.height(),
-    );
+    )
+}
+
+pub fn compress(source: &DynamicImage, mode: ImageMode, num_colors: usize) -> Result<Vec<u8>, String> {
+    // CHECKS
+    assert!(num_colors >= 1 && num_colors <= 256);
+    // SETUP
+    let (_, optimizer) = mode.quantizer_config();
+    let input_pixels = input_pixels(source);
+    let num_colors = num_colors.min(distinct_colors(&input_pixels).max(1));
+    let histogram = Histogram::from_iter(input_pixels.clone());
+    let colorspace = SimpleColorSpace::default();
+    // The quantizer starts out with a single color, each step adds one.
+    let mut quantizer = Quantizer::new(&histogram, &colorspace);
+    for _ in 1..num_colors {
+        quantizer.step();
+        quantizer = quantizer.optimize(&*optimizer, 16);
+    }
+    let palette = quantizer.colors(&colorspace);
     // DONE
-    Ok(out_file)
+    Ok(encode_palette(source, &mode, &input_pixels, &histogram, &colorspace, &palette))
 }
 
-pub fn basic_optimize(source: &DynamicImage) -> Vec<u8> {
-    let vmaf_source = VideoBuffer::from_image(&source).expect("to VideoBuffer");
-    let run = |num_colors: usize| {
-        let mode = ImageMode::Text;
-        let compressed = compress(&source, mode, num_colors).expect("compress png source");
-        let report = {
-            let vmaf_derivative = VideoBuffer::from_png(&compressed).expect("to VideoBuffer");
-            vmaf::get_report(&vmaf_source, &vmaf_derivative)
-        };
-        // println!("vmaf: {}", report);
-        (compressed, report)
-    };
-    let fallback = || {
-        let num_colors = 255;
-        let mode = ImageMode::Text;
-        compress(&source, mode, num_colors).expect("compress png source")
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PngReport {
+    pub class: Class,
+    pub class_meta: crate::classifier::Meta,
+    pub mode: ImageMode,
+    /// `None` for the lossless result.
+    pub num_colors: Option<usize>,
+    /// Score of the chosen palette, lossless results aren't scored.
+    pub score: Option<f64>,
+    /// Alpha error of the chosen palette, see `alpha_error`.
+    pub alpha_error: Option<f64>,
+    /// Number of palettes that were scored.
+    pub probes: usize,
+}
+
+/// The VMAF threshold for palettes, the same for every image class.
+pub const THRESHOLD: f64 = 90.0;
+
+/// Bisects over the palette size (up to the number of distinct colors) for
+/// the smallest palette that passes, assuming scores only improve with more
+/// colors, and keeps it if it's smaller than the lossless re-encode. Images
+/// with transparency are scored flattened, and additionally have to pass
+/// `max_alpha_error`.
+///
+/// Palettes are grown with a single quantizer, so every size is only
+/// quantized once no matter how often the search passes it.
+pub fn basic_optimize(
+    source: &DynamicImage,
+    metric: &dyn QualityMetric,
+    threshold: Option<f64>,
+    options: &PngOptions,
+) -> (Vec<u8>, PngReport) {
+    // SETUP
+    let class_report = crate::classifier::report(source);
+    let mode = options.mode
+        .clone()
+        .unwrap_or_else(|| ImageMode::from_class(&class_report.class));
+    let mut report = PngReport {
+        class: class_report.class,
+        class_meta: class_report.meta,
+        mode: mode.clone(),
+        num_colors: None,
+        score: None,
+        alpha_error: None,
+        probes: 0,
     };
-    // RUN
-    for num_colors in 1..256 {
-        // println!("num_colors: {}", num_colors);
-        let (compressed, report) = run(num_colors);
-        if report >= 90.0 || num_colors <= 5 {
-            return compressed;
+    // LOSSLESS
+    // Always passes, and for photos often smaller than a 256 color palette.
+    let lossless = encode_lossless(source);
+    if options.lossless_only {
+        return (lossless, report);
+    }
+    let threshold = metrics::resolve_threshold(metric, threshold, THRESHOLD);
+    let with_alpha = has_alpha(source);
+    let vmaf_source = VideoBuffer::from_image(&flatten(source)).expect("to VideoBuffer");
+    let (_, optimizer) = mode.quantizer_config();
+    let input_pixels = input_pixels(source);
+    let histogram = Histogram::from_iter(input_pixels.clone());
+    let colorspace = SimpleColorSpace::default();
+    let mut quantizer = Quantizer::new(&histogram, &colorspace);
+    // `palettes[n - 1]` has `n` colors.
+    let mut palettes: Vec<Vec<Color>> = vec![quantizer.colors(&colorspace)];
+    // GO!
+    let (mut low, mut high) = (1usize, distinct_colors(&input_pixels).max(1).min(256));
+    let mut passed: Option<(Vec<u8>, usize, f64, f64)> = None;
+    while low <= high {
+        let num_colors = low + (high - low) / 2;
+        while palettes.len() < num_colors {
+            quantizer.step();
+            quantizer = quantizer.optimize(&*optimizer, 16);
+            palettes.push(quantizer.colors(&colorspace));
+        }
+        let compressed = encode_palette(
+            source,
+            &mode,
+            &input_pixels,
+            &histogram,
+            &colorspace,
+            &palettes[num_colors - 1],
+        );
+        let (score, alpha_error) = {
+            let derivative = ::image::load_from_memory_with_format(
+                    &compressed,
+                    ::image::ImageFormat::PNG,
+                )
+                .expect("load png derivative");
+            let vmaf_derivative = VideoBuffer::from_image(&flatten(&derivative))
+                .expect("to VideoBuffer");
+            let alpha_error = if with_alpha {alpha_error(source, &derivative)} else {0.0};
+            (metric.score(&vmaf_source, &vmaf_derivative), alpha_error)
+        };
+        report.probes = report.probes + 1;
+        if score.passes(threshold) && alpha_error <= options.max_alpha_error {
+            passed = Some((compressed, num_colors, score.value, alpha_error));
+            if num_colors == 1 {
+                break;
+            }
+            high = num_colors - 1;
+        } else {
+            low = num_colors + 1;
+        }
+    }
+    // DONE
+    match passed {
+        Some((compressed, num_colors, score, alpha_error)) if compressed.len() < lossless.len() => {
+            report.num_colors = Some(num_colors);
+            report.score = Some(score);
+            report.alpha_error = Some(alpha_error);
+            (compressed, report)
         }
+        _ => (lossless, report),
     }
-    // OR RUN FALLBACK
-    fallback()
 }
 
 
@@ -140,6 +431,64 @@ pub fn run() {
     let output_path = "assets/output/test.png";
     // LOAD & DECODE
     let img = ::image::open(input_path).expect("load input png");
-    let out = basic_optimize(&img);
+    let (out, _) = basic_optimize(
+        &img,
+        &Vmaf(vmaf::VmafOptions::default()),
+        None,
+        &PngOptions::default(),
+    );
     std::fs::write(output_path, &out);
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::FnMetric;
+
+    #[test]
+    fn test_sort_palette() {
+        let palette = vec![
+            Color::new(255, 255, 255, 255),
+            Color::new(10, 10, 10, 128),
+            Color::new(0, 0, 0, 255),
+            Color::new(0, 0, 0, 0),
+        ];
+        let sorted = sort_palette(&palette)
+            .into_iter()
+            .map(|ix| palette[ix])
+            .collect::<Vec<_>>();
+        assert!(sorted.iter().map(|c| c.a).collect::<Vec<_>>() == vec![0, 128, 255, 255]);
+        assert!(sorted[2].r == 0 && sorted[3].r == 255);
+    }
+
+    #[test]
+    fn test_alpha_error_edges() {
+        let mut source = ::image::RgbaImage::from_pixel(100, 100, ::image::Rgba([0, 0, 0, 255]));
+        source.put_pixel(0, 0, ::image::Rgba([0, 0, 0, 128]));
+        let mut derivative = source.clone();
+        derivative.put_pixel(0, 0, ::image::Rgba([0, 0, 0, 77]));
+        let (source, derivative) = (
+            DynamicImage::ImageRgba8(source),
+            DynamicImage::ImageRgba8(derivative),
+        );
+        // ONLY THE EDGE PIXEL COUNTS, NOT THE OPAQUE BACKGROUND
+        assert!((alpha_error(&source, &derivative) - 0.2).abs() < 1e-9);
+        assert!(alpha_error(&source, &source) == 0.0);
+    }
+
+    #[test]
+    fn test_basic_optimize_single_color() {
+        let source = ::image::load_from_memory(include_bytes!("../../assets/test/l0-blank.png"))
+            .expect("load test image");
+        let options = PngOptions::default();
+        let passing = FnMetric(|_: &VideoBuffer, _: &VideoBuffer| 100.0);
+        let (_, report) = basic_optimize(&source, &passing, Some(90.0), &options);
+        assert!(report.probes == 1);
+        assert!(report.num_colors.map(|x| x == 1).unwrap_or(true));
+        // NOTHING PASSES, THE SEARCH STILL STOPS AT THE ONLY COLOR
+        let failing = FnMetric(|_: &VideoBuffer, _: &VideoBuffer| 0.0);
+        let (_, report) = basic_optimize(&source, &failing, Some(90.0), &options);
+        assert!(report.probes == 1 && report.num_colors.is_none());
+    }
+}
@@ -0,0 +1,200 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use std::ffi::{CStr, c_void};
+use std::os::raw::{c_char, c_int};
+use image::{DynamicImage, GenericImageView, RgbaImage, ImageBuffer};
+use webp_dev::sys::webp::{
+    self as webp_sys,
+    WebPConfig,
+    WebPPicture,
+    WebPData,
+    WebPAnimEncoderOptions,
+    WebPAnimDecoderOptions,
+    WebPAnimInfo,
+};
+
+use crate::data::{Animation, AnimationFrame};
+use crate::codec::webp::encode::lossy::{self, WebpOptions};
+
+///////////////////////////////////////////////////////////////////////////////
+// HELPERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Checks the `VP8X` animation flag, without decoding anything.
+pub fn is_animated(source: &[u8]) -> bool {
+    const ANIMATION_FLAG: u8 = 0x02;
+    match source.get(12..21) {
+        Some(header) => &header[0..4] == b"VP8X" && (header[8] & ANIMATION_FLAG) != 0,
+        None => false,
+    }
+}
+
+fn init_picture(source: &DynamicImage) -> WebPPicture {
+    let (width, height) = source.dimensions();
+    assert!(width < webp_sys::WEBP_MAX_DIMENSION);
+    assert!(height < webp_sys::WEBP_MAX_DIMENSION);
+    let mut picture: WebPPicture = unsafe {std::mem::zeroed()};
+    unsafe {
+        assert!(webp_sys::webp_picture_init(&mut picture) != 0);
+    };
+    picture.use_argb = 1;
+    picture.width = width as i32;
+    picture.height = height as i32;
+    picture.argb_stride = width as i32;
+    unsafe {
+        let mut pixel_data = source
+            .to_rgba()
+            .pixels()
+            .flat_map(|px| px.0.to_vec())
+            .collect::<Vec<_>>();
+        let status = webp_sys::webp_picture_import_rgba(
+            &mut picture,
+            pixel_data.as_mut_ptr(),
+            (width * 4) as i32,
+        );
+        assert!(status != 0);
+    };
+    picture
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// DECODER
+///////////////////////////////////////////////////////////////////////////////
+
+/// Decode every frame (composited onto the full canvas) of an animated
+/// WebP. Also works for still images, which yield a single frame.
+pub fn decode(source: &[u8]) -> Result<Animation, String> {
+    unsafe {
+        let mut options: WebPAnimDecoderOptions = std::mem::zeroed();
+        assert!(webp_sys::WebPAnimDecoderOptionsInitInternal(
+            &mut options,
+            webp_sys::WEBP_DEMUX_ABI_VERSION as c_int,
+        ) != 0);
+        options.color_mode = webp_sys::MODE_RGBA;
+        options.use_threads = 0;
+        let data = WebPData {
+            bytes: source.as_ptr(),
+            size: source.len(),
+        };
+        let decoder = webp_sys::WebPAnimDecoderNewInternal(
+            &data,
+            &options,
+            webp_sys::WEBP_DEMUX_ABI_VERSION as c_int,
+        );
+        if decoder.is_null() {
+            return Err(String::from("invalid animated webp"));
+        }
+        let mut info: WebPAnimInfo = std::mem::zeroed();
+        assert!(webp_sys::WebPAnimDecoderGetInfo(decoder, &mut info) != 0);
+        let (width, height) = (info.canvas_width, info.canvas_height);
+        let mut frames = Vec::<AnimationFrame>::with_capacity(info.frame_count as usize);
+        let mut last_timestamp: c_int = 0;
+        while webp_sys::WebPAnimDecoderHasMoreFrames(decoder) != 0 {
+            let mut buffer: *mut u8 = std::ptr::null_mut();
+            let mut timestamp: c_int = 0;
+            if webp_sys::WebPAnimDecoderGetNext(decoder, &mut buffer, &mut timestamp) == 0 {
+                webp_sys::WebPAnimDecoderDelete(decoder);
+                return Err(String::from("failed to decode animation frame"));
+            }
+            // OWNED BY THE DECODER, VALID UNTIL THE NEXT CALL
+            let size = (width * height * 4) as usize;
+            let pixels = std::slice::from_raw_parts(buffer, size).to_vec();
+            let image: RgbaImage = ImageBuffer::from_vec(width, height, pixels).expect("to ImageBuffer");
+            frames.push(AnimationFrame {
+                image: DynamicImage::ImageRgba8(image),
+                delay: (timestamp - last_timestamp).max(0) as u32,
+            });
+            last_timestamp = timestamp;
+        }
+        webp_sys::WebPAnimDecoderDelete(decoder);
+        if frames.is_empty() {
+            return Err(String::from("animated webp without frames"));
+        }
+        Ok(Animation {
+            frames,
+            loop_count: info.loop_count,
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// ENCODER
+///////////////////////////////////////////////////////////////////////////////
+
+/// Encode with libwebp's `WebPAnimEncoder`, every frame uses the same
+/// (lossy) quality.
+pub fn encode(source: &Animation, q: f32, options: &WebpOptions) -> Result<Vec<u8>, String> {
+    let (width, height) = source.dimensions();
+    let config: WebPConfig = lossy::init_config(q, options);
+    unsafe {
+        let mut anim_options: WebPAnimEncoderOptions = std::mem::zeroed();
+        let status = webp_sys::WebPAnimEncoderOptionsInitInternal(
+            &mut anim_options,
+            webp_sys::WEBP_MUX_ABI_VERSION as c_int,
+        );
+        if status == 0 {
+            return Err(String::from("WebPAnimEncoderOptionsInit failed"));
+        }
+        anim_options.anim_params.loop_count = source.loop_count as c_int;
+        // SLOWER, BUT PICKS THE SMALLEST SUB-FRAME RECTANGLES
+        anim_options.minimize_size = 1;
+        let encoder = webp_sys::WebPAnimEncoderNewInternal(
+            width as c_int,
+            height as c_int,
+            &anim_options,
+            webp_sys::WEBP_MUX_ABI_VERSION as c_int,
+        );
+        if encoder.is_null() {
+            return Err(String::from("WebPAnimEncoderNew failed"));
+        }
+        let fail = |stage: &str| -> Result<Vec<u8>, String> {
+            let error = CStr::from_ptr(webp_sys::WebPAnimEncoderGetError(encoder))
+                .to_string_lossy()
+                .into_owned();
+            webp_sys::WebPAnimEncoderDelete(encoder);
+            Err(format!("{} failed: {}", stage, error))
+        };
+        // FRAMES
+        let mut timestamp: c_int = 0;
+        for frame in source.frames.iter() {
+            if frame.image.dimensions() != (width, height) {
+                webp_sys::WebPAnimEncoderDelete(encoder);
+                return Err(String::from("animation frames differ in size"));
+            }
+            let mut picture = init_picture(&frame.image);
+            let status = webp_sys::WebPAnimEncoderAdd(
+                encoder,
+                &mut picture,
+                timestamp,
+                &config,
+            );
+            webp_sys::webp_picture_free(&mut picture);
+            if status == 0 {
+                return fail("WebPAnimEncoderAdd");
+            }
+            timestamp = timestamp + frame.delay as c_int;
+        }
+        // THE LAST FRAME'S DURATION
+        let status = webp_sys::WebPAnimEncoderAdd(
+            encoder,
+            std::ptr::null_mut(),
+            timestamp,
+            std::ptr::null(),
+        );
+        if status == 0 {
+            return fail("WebPAnimEncoderAdd");
+        }
+        // ASSEMBLE
+        let mut data: WebPData = std::mem::zeroed();
+        if webp_sys::WebPAnimEncoderAssemble(encoder, &mut data) == 0 {
+            return fail("WebPAnimEncoderAssemble");
+        }
+        let output = std::slice::from_raw_parts(data.bytes, data.size).to_vec();
+        // CLEANUP
+        webp_sys::WebPFree(data.bytes as *mut c_void);
+        webp_sys::WebPAnimEncoderDelete(encoder);
+        // DONE
+        Ok(output)
+    }
+}
@@ -1,6 +1,7 @@
 use std::ffi::{CString, c_void};
 use std::os::raw::{c_char, c_int};
 use libc::{size_t, c_float};
+use serde::{Serialize, Deserialize};
 use image::{DynamicImage, GenericImage, GenericImageView};
 use webp_dev::sys::webp::{
     self as webp_sys,
@@ -9,34 +10,125 @@ use webp_dev::sys::webp::{
     WebPMemoryWriter,
 };
 
-pub fn init_config(q: f32) -> WebPConfig {
+///////////////////////////////////////////////////////////////////////////////
+// ENCODER OPTIONS
+///////////////////////////////////////////////////////////////////////////////
+
+/// The subset of `WebPConfig` fields that are worth tuning per deployment.
+///
+/// The defaults match what the encoder always used, i.e. the slowest and
+/// smallest settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebpOptions {
+    /// Quality/speed trade-off, `0` (fast) to `6` (slower, smaller).
+    pub method: u8,
+    /// Spatial noise shaping strength, `0` to `100`.
+    pub sns_strength: u8,
+    /// Deblocking filter strength, `0` (off) to `100`.
+    pub filter_strength: u8,
+    /// Deblocking filter sharpness, `0` (sharpest) to `7`.
+    pub filter_sharpness: u8,
+    /// Number of segments, `1` to `4`.
+    pub segments: u8,
+    /// Preprocessing filter, `0` (none), `1` (segment-smooth) or
+    /// `2` (pseudo-random dithering).
+    pub preprocessing: u8,
+    /// Use the slower but more accurate RGB to YUV conversion.
+    pub use_sharp_yuv: bool,
+    /// Let libwebp aim for this many bytes, overrides `quality`.
+    pub target_size: Option<u32>,
+    /// Let libwebp aim for this PSNR (in dB), overrides `quality`.
+    pub target_psnr: Option<f32>,
+    /// Number of entropy-analysis passes, `1` to `10`. Only matters with
+    /// `target_size` or `target_psnr`, where more passes converge closer
+    /// to the target.
+    pub passes: u8,
+    /// Use multiple threads where libwebp supports it.
+    pub multithreading: bool,
+}
+
+impl Default for WebpOptions {
+    fn default() -> Self {
+        WebpOptions {
+            method: 6,
+            sns_strength: 50,
+            filter_strength: 60,
+            filter_sharpness: 0,
+            segments: 4,
+            preprocessing: 0,
+            use_sharp_yuv: true,
+            target_size: None,
+            target_psnr: None,
+            passes: 1,
+            multithreading: false,
+        }
+    }
+}
+
+impl WebpOptions {
+    /// Fast preset, roughly what `cwebp -m 2` does.
+    pub fn fast() -> Self {
+        WebpOptions {
+            method: 2,
+            use_sharp_yuv: false,
+            multithreading: true,
+            ..WebpOptions::default()
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// ENCODER
+///////////////////////////////////////////////////////////////////////////////
+
+pub fn init_config(q: f32, options: &WebpOptions) -> WebPConfig {
     let mut config: WebPConfig = unsafe {std::mem::zeroed()};
     unsafe {
         webp_sys::webp_config_init(&mut config);
-        webp_sys::webp_validate_config(&mut config);
     };
     config.quality = q;
     config.lossless = 0;
-    config.method = 6;
+    config.method = options.method.min(6) as c_int;
+    config.sns_strength = options.sns_strength.min(100) as c_int;
+    config.filter_strength = options.filter_strength.min(100) as c_int;
+    config.filter_sharpness = options.filter_sharpness.min(7) as c_int;
+    config.segments = options.segments.max(1).min(4) as c_int;
+    config.preprocessing = options.preprocessing.min(2) as c_int;
+    config.use_sharp_yuv = options.use_sharp_yuv as c_int;
+    config.thread_level = options.multithreading as c_int;
+    config.pass = options.passes.max(1).min(10) as c_int;
+    if let Some(target_size) = options.target_size {
+        config.target_size = target_size as c_int;
+    }
+    if let Some(target_psnr) = options.target_psnr {
+        config.target_PSNR = target_psnr as c_float;
+    }
+    unsafe {
+        assert!(webp_sys::webp_validate_config(&mut config) != 0);
+    };
     config
 }
 
-pub fn init_picture(source: &DynamicImage) -> (WebPPicture, *mut WebPMemoryWriter) {
+pub fn init_picture(source: &DynamicImage, use_sharp_yuv: bool) -> (WebPPicture, *mut WebPMemoryWriter) {
     // SETUP
     let (mut picture, writer) = crate::codec::webp::encode::lossless::init_picture(source);
     // CONVERT
-    unsafe {
-        assert!(webp_sys::webp_picture_sharp_argb_to_yuva(&mut picture) != 0);
-        assert!(picture.use_argb == 0);
-        assert!(!picture.y.is_null());
-    };
+    // Otherwise libwebp does the (regular) ARGB to YUV conversion itself.
+    if use_sharp_yuv {
+        unsafe {
+            assert!(webp_sys::webp_picture_sharp_argb_to_yuva(&mut picture) != 0);
+            assert!(picture.use_argb == 0);
+            assert!(!picture.y.is_null());
+        };
+    }
     // DONE
     (picture, writer)
 }
 
-pub fn encode(source: &DynamicImage, q: f32) -> Vec<u8> {
-    let config = init_config(q);
-    let (mut picture, writer_ptr) = init_picture(&source);
+pub fn encode(source: &DynamicImage, q: f32, options: &WebpOptions) -> Vec<u8> {
+    let config = init_config(q, options);
+    let (mut picture, writer_ptr) = init_picture(&source, options.use_sharp_yuv);
     unsafe {
         assert!(webp_sys::webp_encode(&config, &mut picture) != 0);
     };
@@ -55,4 +147,4 @@ pub fn encode(source: &DynamicImage, q: f32) -> Vec<u8> {
     };
     // DONE
     output
-}
\ No newline at end of file
+}
@@ -1,3 +1,4 @@
 pub mod encode;
 pub mod decode;
-pub mod opt;
\ No newline at end of file
+pub mod opt;
+pub mod anim;
\ No newline at end of file
@@ -1,80 +1,93 @@
+use std::cell::Cell;
 use std::path::PathBuf;
 use itertools::Itertools;
 use serde::{Serialize, Deserialize};
 use rayon::prelude::*;
 use image::{DynamicImage, GenericImage, GenericImageView};
-use crate::data::{VideoBuffer, Yuv420P};
+use crate::data::{VideoBuffer, Yuv420P, Animation};
 use crate::classifier::{self, Class};
 use crate::vmaf;
-use crate::codec::webp::encode::lossy::{encode};
+use crate::metrics::{self, QualityMetric, Score};
+use crate::codec::webp::encode::lossy::{encode, WebpOptions};
+use crate::codec::webp::anim;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OutMeta {
     pub class: Class,
+    pub class_meta: classifier::Meta,
     pub score: f64,
-    pub end_q: u32,
+    /// `None` when libwebp's rate control picked the quality, see
+    /// `target_psnr`.
+    pub end_q: Option<u32>,
+    /// The PSNR libwebp converged on, `opt_fast` only.
+    pub target_psnr: Option<f64>,
     pub passed: bool,
+    /// Number of encodes that were scored.
+    pub probes: usize,
     pub input_path: Option<PathBuf>,
     pub output_path: Option<PathBuf>,
 }
 
-pub fn opt(source: &DynamicImage) -> (Vec<u8>, OutMeta) {
+/// The VMAF threshold for an image class, also `WebpEncoder::class_threshold`.
+pub fn class_threshold(class: &Class, dimensions: (u32, u32)) -> f64 {
+    let (width, height) = dimensions;
+    let is_small = {
+        (width * height) < (600 * 600)
+    };
+    match class {
+        Class::L0 | Class::L1 | Class::L2 if is_small => 99.0,
+        Class::L0 | Class::L1 | Class::L2 => 95.0,
+        Class::M1 if is_small => 98.0,
+        Class::M1 => 90.0,
+        Class::H1 | Class::H2 if is_small => 70.0,
+        Class::H1 => 60.0,
+        Class::H2 => 55.0,
+    }
+}
+
+pub fn opt(
+    source: &DynamicImage,
+    options: &WebpOptions,
+    metric: &dyn QualityMetric,
+    threshold: Option<f64>,
+) -> (Vec<u8>, OutMeta) {
     let class = classifier::report(source);
+    let threshold = metrics::resolve_threshold(
+        metric,
+        threshold,
+        class_threshold(&class.class, source.dimensions()),
+    );
     let vmaf_source = VideoBuffer::from_image(source)
         .expect("image to yuv frame");
-    let run = |q: f32| -> (Vec<u8>, f64) {
-        let compressed = encode(source, q);
+    let probes = Cell::new(0);
+    let run = |q: f32| -> (Vec<u8>, Score) {
+        probes.set(probes.get() + 1);
+        let compressed = encode(source, q, options);
         let score = {
             let vmaf_derivative = crate::codec::webp::decode::decode(&compressed);
             let vmaf_derivative = VideoBuffer::from_image(&vmaf_derivative)
                 .expect("image to yuv frame");
-            vmaf::get_report(&vmaf_source, &vmaf_derivative)
+            metric.score(&vmaf_source, &vmaf_derivative)
         };
         (compressed, score)
     };
     let fallback = |end_q, score| {
-        let compressed = encode(source, 100.0);
+        let compressed = encode(source, 100.0, options);
         let meta = OutMeta {
             class: class.class.clone(),
+            class_meta: class.meta.clone(),
             score,
-            end_q,
+            end_q: Some(end_q),
+            target_psnr: None,
             passed: false,
+            probes: probes.get(),
             input_path: None,
             output_path: None,
         };
         (compressed, meta)
     };
-    let terminate = |score: f64| {
-        let (width, height) = source.dimensions();
-        let is_small = {
-            (width * height) < (600 * 600)
-        };
-        let mut threshold;
-        match class.class {
-            Class::L0 | Class::L1 | Class::L2 if is_small => {
-                threshold = 99.0;
-            }
-            Class::L0 | Class::L1 | Class::L2 => {
-                threshold = 95.0;
-            }
-            Class::M1 => {
-                if is_small {
-                    threshold = 98.0;
-                } else {
-                    threshold = 90.0;
-                }
-            }
-            Class::H1 | Class::H2 if is_small => {
-                threshold = 70.0;
-            }
-            Class::H1 => {
-                threshold = 60.0;
-            }
-            Class::H2 => {
-                threshold = 55.0;
-            }
-        }
-        score >= threshold
+    let terminate = |score: Score| {
+        score.passes(threshold)
     };
     // SEARCH
     let start_q = {
@@ -129,9 +142,12 @@ pub fn opt(source: &DynamicImage) -> (Vec<u8>, OutMeta) {
         if terminate(score) {
             let meta = OutMeta {
                 class: class.class.clone(),
-                score,
-                end_q: q,
+                class_meta: class.meta.clone(),
+                score: score.value,
+                end_q: Some(q),
+                target_psnr: None,
                 passed: true,
+                probes: probes.get(),
                 input_path: None,
                 output_path: None,
             };
@@ -141,5 +157,214 @@ pub fn opt(source: &DynamicImage) -> (Vec<u8>, OutMeta) {
     // FALLBACK
     let last_q = last_q.expect("should run at least once");
     let last_score = last_score.expect("should run at least once");
-    fallback(last_q, last_score)
-}
\ No newline at end of file
+    fallback(last_q, last_score.value)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// FAST MODE
+///////////////////////////////////////////////////////////////////////////////
+
+/// The quality used for the single VMAF probe.
+const PROBE_Q: f32 = 75.0;
+
+/// Rough slope between PSNR and VMAF in the range the thresholds live in,
+/// i.e. one dB buys about four VMAF points.
+const DB_PER_VMAF_POINT: f64 = 0.25;
+
+fn luma_psnr(source: &Yuv420P, derivative: &Yuv420P) -> f64 {
+    assert!(source.dimensions() == derivative.dimensions());
+    let sum: f64 = source
+        .y()
+        .iter()
+        .zip(derivative.y().iter())
+        .map(|(a, b)| {
+            let diff = (*a as f64) - (*b as f64);
+            diff * diff
+        })
+        .sum();
+    let mse = sum / (source.luma_size() as f64);
+    if mse == 0.0 {
+        return std::f64::INFINITY;
+    }
+    10.0 * ((255.0 * 255.0) / mse).log10()
+}
+
+/// Like `opt`, but instead of searching over `quality`, a single probe
+/// estimates the PSNR that corresponds to the VMAF threshold, and libwebp's
+/// own multi-pass rate control converges on it.
+///
+/// The result is verified with one more VMAF check. If that fails we fall
+/// back to the exhaustive search in `opt`.
+pub fn opt_fast(
+    source: &DynamicImage,
+    options: &WebpOptions,
+    metric: &dyn QualityMetric,
+    threshold: Option<f64>,
+) -> (Vec<u8>, OutMeta) {
+    // THE PSNR ESTIMATE IS ONLY CALIBRATED AGAINST VMAF
+    if !metric.is_vmaf() {
+        return opt(source, options, metric, threshold);
+    }
+    let class = classifier::report(source);
+    let threshold = metrics::resolve_threshold(
+        metric,
+        threshold,
+        class_threshold(&class.class, source.dimensions()),
+    );
+    let vmaf_source = VideoBuffer::from_image(source)
+        .expect("image to yuv frame");
+    let yuv_source = &vmaf_source.as_frames()[0];
+    let probes = Cell::new(0);
+    let run = |q: f32, options: &WebpOptions| -> (Vec<u8>, Score, f64) {
+        probes.set(probes.get() + 1);
+        let compressed = encode(source, q, options);
+        let vmaf_derivative = crate::codec::webp::decode::decode(&compressed);
+        let vmaf_derivative = VideoBuffer::from_image(&vmaf_derivative)
+            .expect("image to yuv frame");
+        let psnr = luma_psnr(yuv_source, &vmaf_derivative.as_frames()[0]);
+        let score = metric.score(&vmaf_source, &vmaf_derivative);
+        (compressed, score, psnr)
+    };
+    // PROBE
+    let (_, probe_score, probe_psnr) = run(PROBE_Q, options);
+    let target_psnr = {
+        let x = probe_psnr + (threshold - probe_score.value) * DB_PER_VMAF_POINT;
+        // The probe may be lossless enough to report an infinite PSNR.
+        if x.is_finite() {x.max(20.0).min(60.0)} else {60.0}
+    };
+    // LET LIBWEBP CONVERGE
+    let target_options = WebpOptions {
+        target_psnr: Some(target_psnr as f32),
+        passes: options.passes.max(6),
+        ..options.clone()
+    };
+    let (compressed, score, _) = run(PROBE_Q, &target_options);
+    if score.passes(threshold) {
+        let meta = OutMeta {
+            class: class.class.clone(),
+            class_meta: class.meta.clone(),
+            score: score.value,
+            end_q: None,
+            target_psnr: Some(target_psnr),
+            passed: true,
+            probes: probes.get(),
+            input_path: None,
+            output_path: None,
+        };
+        return (compressed, meta);
+    }
+    // FALLBACK
+    let (compressed, mut meta) = opt(source, options, metric, Some(threshold));
+    meta.probes = meta.probes + probes.get();
+    (compressed, meta)
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ANIMATION
+///////////////////////////////////////////////////////////////////////////////
+
+/// Quality search for animated WebP output, scored over all frames.
+///
+/// Every probe encodes the whole animation, so this bisects over `quality`
+/// instead of stepping through it.
+pub fn opt_animation(
+    source: &Animation,
+    options: &WebpOptions,
+    metric: &dyn QualityMetric,
+    threshold: Option<f64>,
+) -> Result<(Vec<u8>, OutMeta), String> {
+    let class = classifier::report(source.first_frame());
+    let threshold = metrics::resolve_threshold(
+        metric,
+        threshold,
+        class_threshold(&class.class, source.dimensions()),
+    );
+    let vmaf_source = VideoBuffer::from_animation(source)
+        .map_err(|_| String::from("animation to yuv frames"))?;
+    let probes = Cell::new(0);
+    let run = |q: u32| -> Result<(Vec<u8>, Score), String> {
+        probes.set(probes.get() + 1);
+        let compressed = anim::encode(source, q as f32, options)?;
+        let score = {
+            let vmaf_derivative = anim::decode(&compressed)?.resample_like(source);
+            let vmaf_derivative = VideoBuffer::from_animation(&vmaf_derivative)
+                .map_err(|_| String::from("animation to yuv frames"))?;
+            metric.score(&vmaf_source, &vmaf_derivative)
+        };
+        Ok((compressed, score))
+    };
+    // SEARCH
+    let mut low = 0;
+    let mut high = 100;
+    let mut passed: Option<(Vec<u8>, u32, f64)> = None;
+    let mut last_score = 0.0;
+    while low <= high {
+        let q = (low + high) / 2;
+        let (compressed, score) = run(q)?;
+        last_score = score.value;
+        if score.passes(threshold) {
+            passed = Some((compressed, q, score.value));
+            if q == 0 {
+                break;
+            }
+            high = q - 1;
+        } else {
+            low = q + 1;
+        }
+    }
+    match passed {
+        Some((compressed, end_q, score)) => {
+            let meta = OutMeta {
+                class: class.class.clone(),
+                class_meta: class.meta.clone(),
+                score,
+                end_q: Some(end_q),
+                target_psnr: None,
+                passed: true,
+                probes: probes.get(),
+                input_path: None,
+                output_path: None,
+            };
+            Ok((compressed, meta))
+        }
+        None => {
+            let compressed = anim::encode(source, 100.0, options)?;
+            let meta = OutMeta {
+                class: class.class.clone(),
+                class_meta: class.meta.clone(),
+                score: last_score,
+                end_q: Some(100),
+                target_psnr: None,
+                passed: false,
+                probes: probes.get(),
+                input_path: None,
+                output_path: None,
+            };
+            Ok((compressed, meta))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::FnMetric;
+    use crate::testing::test_image;
+
+    #[test]
+    fn test_opt_always_passes() {
+        let metric = FnMetric(|_: &VideoBuffer, _: &VideoBuffer| 100.0);
+        let (_, meta) = opt(&test_image(), &WebpOptions::default(), &metric, Some(90.0));
+        assert!(meta.passed);
+        assert!(meta.end_q.map(|q| q <= 1).unwrap_or(false));
+    }
+
+    #[test]
+    fn test_opt_never_passes() {
+        let metric = FnMetric(|_: &VideoBuffer, _: &VideoBuffer| 0.0);
+        let (_, meta) = opt(&test_image(), &WebpOptions::default(), &metric, Some(90.0));
+        assert!(!meta.passed);
+        assert!(meta.score == 0.0);
+    }
+}
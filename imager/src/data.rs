@@ -202,32 +202,93 @@ impl<'de> Deserialize<'de> for OutputSize {
 // MISC HELPERS
 ///////////////////////////////////////////////////////////////////////////////
 
-pub fn ensure_even_reslution(source: &DynamicImage) -> DynamicImage {
-    let (width, height) = source.dimensions();
-    // ENSURE EVEN
-    let even_width = (width % 2) == 0;
-    let even_height = (height % 2) == 0;
-    if (!even_width) || (!even_height) {
-        let new_width = {
-            if !even_width {
-                width - 1
-            } else {
-                width
-            }
-        };
-        let new_height = {
-            if !even_height {
-                height - 1
-            } else {
-                height
+pub use crate::shared::ensure_even_reslution;
+
+///////////////////////////////////////////////////////////////////////////////
+// ANIMATION
+///////////////////////////////////////////////////////////////////////////////
+
+/// A fully composited animation frame, i.e. the whole canvas.
+#[derive(Clone)]
+pub struct AnimationFrame {
+    pub image: DynamicImage,
+    /// How long the frame is shown, in milliseconds.
+    pub delay: u32,
+}
+
+#[derive(Clone)]
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    /// Zero means loop forever.
+    pub loop_count: u32,
+}
+
+impl Animation {
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+    pub fn first_frame(&self) -> &DynamicImage {
+        &self.frames.first().expect("empty animation").image
+    }
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.first_frame().dimensions()
+    }
+    pub fn duration(&self) -> u32 {
+        self.frames.iter().map(|x| x.delay).sum()
+    }
+    /// Start time (in milliseconds) of every frame.
+    pub fn timestamps(&self) -> Vec<u32> {
+        self.frames
+            .iter()
+            .scan(0, |acc, frame| {
+                let start = *acc;
+                *acc = *acc + frame.delay;
+                Some(start)
+            })
+            .collect()
+    }
+    /// The frame that is visible at the given time (in milliseconds).
+    pub fn frame_at(&self, time: u32) -> &AnimationFrame {
+        let mut end = 0;
+        for frame in self.frames.iter() {
+            end = end + frame.delay;
+            if time < end {
+                return frame;
             }
-        };
-        let new_image = source
-            .clone()
-            .crop(0, 0, new_width, new_height);
-        new_image
-    } else {
-        source.clone()
+        }
+        self.frames.last().expect("empty animation")
+    }
+    /// Resample onto the timeline of `reference`.
+    ///
+    /// Encoders are free to merge identical consecutive frames, so this is
+    /// needed before comparing frame by frame.
+    pub fn resample_like(&self, reference: &Animation) -> Animation {
+        let frames = reference
+            .timestamps()
+            .into_iter()
+            .zip(reference.frames.iter())
+            .map(|(time, reference_frame)| AnimationFrame {
+                image: self.frame_at(time).image.clone(),
+                delay: reference_frame.delay,
+            })
+            .collect::<Vec<_>>();
+        Animation {
+            frames,
+            loop_count: self.loop_count,
+        }
+    }
+    pub fn map_frames<F: Fn(&DynamicImage) -> DynamicImage + Sync>(&self, f: F) -> Animation {
+        let frames = self.frames
+            .par_iter()
+            .map(|frame| AnimationFrame {
+                image: f(&frame.image),
+                delay: frame.delay,
+            })
+            .collect::<Vec<_>>();
+        Animation {
+            frames,
+            loop_count: self.loop_count,
+        }
     }
 }
 
@@ -506,6 +567,24 @@ impl VideoBuffer {
     pub fn from_image(source: &DynamicImage) -> Result<Self, ()> {
         Ok(VideoBuffer::singleton(Yuv420P::from_image(source)?))
     }
+    /// One YUV frame per animation frame, frame timing is ignored.
+    pub fn from_animation(source: &Animation) -> Result<Self, ()> {
+        let frames = source.frames
+            .par_iter()
+            .map(|frame| Yuv420P::from_image(&frame.image))
+            .collect::<Result<Vec<_>, ()>>()?;
+        VideoBuffer::from_frames(frames)
+    }
+    pub fn from_frames(frames: Vec<Yuv420P>) -> Result<Self, ()> {
+        let (width, height) = frames.first().ok_or(())?.dimensions();
+        assert!(frames.iter().all(|x| x.dimensions() == (width, height)));
+        Ok(VideoBuffer {
+            width,
+            height,
+            frames: Arc::new(frames),
+            cursor: 0,
+        })
+    }
     pub fn singleton(frame: Yuv420P) -> Self {
         VideoBuffer {
             width: frame.width,
@@ -2,5 +2,11 @@
 pub mod classifier;
 pub mod codec;
 pub mod vmaf;
+pub mod metrics;
 pub mod data;
 pub mod api;
+pub mod shared;
+#[cfg(test)]
+mod testing;
+
+pub use api::{analyze, analyze_with_debug_images};
@@ -2,15 +2,19 @@
 pub mod classifier;
 pub mod codec;
 pub mod vmaf;
+pub mod metrics;
 pub mod data;
 pub mod api;
+pub mod shared;
+#[cfg(test)]
+mod testing;
 
 use std::sync::{Arc, Mutex};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use rayon::prelude::*;
 use serde::{Serialize, Deserialize};
 use structopt::StructOpt;
-use structopt::clap::ArgGroup;
+use structopt::clap::{ArgGroup, AppSettings};
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
 
@@ -31,6 +35,30 @@ enum OutputType {
     Replace,
 }
 
+fn expand_inputs(inputs: &[String]) -> Vec<PathBuf> {
+    inputs
+        .to_vec()
+        .into_iter()
+        .filter_map(|x| glob::glob(&x).ok())
+        .map(|x| x.collect::<Vec<_>>())
+        .flatten()
+        .filter_map(Result::ok)
+        .collect::<Vec<_>>()
+}
+
+/// `report` is `None` (and `error` set) for inputs that couldn't be
+/// analyzed, the other inputs are still reported.
+#[derive(Serialize)]
+struct AnalyzeEntry {
+    input_path: PathBuf,
+    #[serde(flatten)]
+    report: Option<crate::classifier::Report>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
 impl OutputType {
     pub fn is_dir(&self) -> bool {
         match self {
@@ -56,6 +84,27 @@ impl OutputType {
 // CLI FRONTEND
 ///////////////////////////////////////////////////////////////////////////////
 
+#[derive(Debug, Clone, StructOpt)]
+pub enum Subcommand {
+    /// Classify images without optimizing them.
+    ///
+    /// Prints a JSON report per input, with the class, white backdrop and
+    /// the edge/region/component stats the class is derived from. Inputs
+    /// that fail only get an `error`.
+    Analyze {
+        /// Input file(s) path.
+        #[structopt(short, long, required = true, min_values = 1)]
+        inputs: Vec<String>,
+
+        /// Save the classifier's intermediate images under this directory,
+        /// in a sub-directory per input file, named `{index}-{file stem}`
+        /// so inputs with the same name don't collide. Also reported as
+        /// `debug_dir` in the JSON output.
+        #[structopt(long, parse(from_os_str))]
+        debug_dir: Option<PathBuf>,
+    },
+}
+
 /// The Imager CLI Interface
 /// 
 /// Output type much be one of: `--output-file`, `--output-dir`, or `--replace`.
@@ -64,8 +113,13 @@ impl OutputType {
     name = "imager",
     // rename_all = "kebab-case",
     group = (ArgGroup::with_name("output_type").required(true)),
+    setting = AppSettings::SubcommandsNegateReqs,
 )]
 pub struct Command {
+    #[structopt(subcommand)]
+    subcommand: Option<Subcommand>,
+
+
     /// Input file(s) path.
     #[structopt(short, long, required = true, min_values = 1)]
     inputs: Vec<String>,
@@ -103,7 +157,39 @@ pub struct Command {
     #[structopt(long)]
     max_size: Option<Resolution>,
 
-    /// Internal. No stability guarantees.
+    /// Use a faster search where available (currently WebP only).
+    /// 
+    /// Does a single quality probe and lets the encoder converge on the
+    /// target, at the cost of slightly larger files.
+    #[structopt(long)]
+    fast: bool,
+
+    /// PNG output is only re-encoded losslessly, without reducing colors.
+    #[structopt(long)]
+    lossless: bool,
+
+    /// VMAF model, one of `4k`, `default` (1080p), `phone`, or a `.pkl`
+    /// model file.
+    #[structopt(long, default_value = "4k")]
+    vmaf_model: crate::vmaf::VmafModel,
+
+    /// Quality metric that drives the search.
+    ///
+    /// One of `vmaf`, `psnr`, `ssim`, `ms-ssim`, or `ciede2000`. The
+    /// alternatives to VMAF are faster, but less accurate.
+    #[structopt(long, default_value = "vmaf")]
+    metric: crate::metrics::Metric,
+
+    /// Minimum score of the chosen metric (for `ciede2000` the maximum
+    /// color difference).
+    ///
+    /// Defaults to per-class thresholds for VMAF, and fixed thresholds for
+    /// the other metrics.
+    #[structopt(long)]
+    threshold: Option<f64>,
+
+    /// Write a JSON report of every output (see `api::OutMeda`) to this
+    /// file, e.g. sizes, chosen quality and timings.
     #[structopt(long, parse(from_os_str))]
     log_file: Option<PathBuf>,
 
@@ -115,14 +201,13 @@ pub struct Command {
 
 impl Command {
     pub fn run(&self) {
-        let inputs = self.inputs
-            .clone()
-            .into_iter()
-            .filter_map(|x| glob::glob(&x).ok())
-            .map(|x| x.collect::<Vec<_>>())
-            .flatten()
-            .filter_map(Result::ok)
-            .collect::<Vec<_>>();
+        match &self.subcommand {
+            Some(Subcommand::Analyze {inputs, debug_dir}) => {
+                return analyze(inputs, debug_dir.clone());
+            }
+            None => (),
+        }
+        let inputs = expand_inputs(&self.inputs);
         if inputs.len() > 1 && self.output_file.is_some() {
             panic!("Output file isn’t valid for multiple input file paths, maybe use `--output-dir`?");
         }
@@ -160,6 +245,16 @@ impl Command {
             if let Some(max_size) = self.max_size.clone() {
                 opt_job.max_size(max_size);
             }
+            opt_job.fast_mode(self.fast);
+            opt_job.png_options(crate::codec::png::PngOptions {
+                lossless_only: self.lossless,
+                ..Default::default()
+            });
+            opt_job.vmaf_model(self.vmaf_model.clone()).expect("invalid vmaf model");
+            opt_job.metric(self.metric);
+            if let Some(threshold) = self.threshold {
+                opt_job.threshold(threshold);
+            }
             let (encoded, mut out_meta) = opt_job.run(self.extreme).expect("opt job failed");
             out_meta.input_path = Some(input_path.clone());
             out_meta.output_path = None;
@@ -234,6 +329,55 @@ impl Command {
 }
 
 
+/// `debug_dir` is the sub-directory for this input, if any.
+fn analyze_entry(
+    input_path: &Path,
+    debug_dir: Option<&Path>,
+) -> Result<crate::classifier::Report, String> {
+    let source = std::fs::read(input_path).map_err(|e| format!("read input file: {}", e))?;
+    let report = match debug_dir {
+        Some(_) => crate::api::analyze_with_debug_images(&source),
+        None => crate::api::analyze(&source),
+    };
+    let report = report.map_err(|_| String::from("decode input file"))?;
+    if let (Some(dir), Some(images)) = (debug_dir, &report.debug_images) {
+        images.save(dir)?;
+    }
+    Ok(report)
+}
+
+fn analyze(inputs: &[String], debug_dir: Option<PathBuf>) {
+    let inputs = expand_inputs(inputs);
+    if inputs.is_empty() {
+        eprintln!("[warning] no (or missing) input files given");
+    }
+    let entries = inputs
+        .into_par_iter()
+        .enumerate()
+        .map(|(index, input_path)| {
+            let debug_dir = debug_dir.as_ref().map(|dir| {
+                let file_stem = input_path
+                    .file_stem()
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                dir.join(format!("{}-{}", index, file_stem))
+            });
+            match analyze_entry(&input_path, debug_dir.as_ref().map(PathBuf::as_path)) {
+                Ok(report) => {
+                    AnalyzeEntry {input_path, report: Some(report), debug_dir, error: None}
+                }
+                Err(error) => {
+                    eprintln!("[error] {:?}: {}", input_path, error);
+                    AnalyzeEntry {input_path, report: None, debug_dir: None, error: Some(error)}
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let output = serde_json::to_string_pretty(&entries).expect("to json str failed");
+    println!("{}", output);
+}
+
+
 ///////////////////////////////////////////////////////////////////////////////
 // MAIN
 ///////////////////////////////////////////////////////////////////////////////
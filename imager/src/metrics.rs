@@ -0,0 +1,727 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//! Pure-Rust quality metrics, as lighter alternatives to VMAF.
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use image::{DynamicImage, GenericImageView};
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use crate::data::{Yuv420P, VideoBuffer};
+use crate::vmaf::{self, VmafOptions};
+
+
+///////////////////////////////////////////////////////////////////////////////
+// INTERFACE
+///////////////////////////////////////////////////////////////////////////////
+
+/// Whether `value` is good enough depends on the metric, e.g. lower is
+/// better for color differences.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    pub value: f64,
+    pub higher_is_better: bool,
+}
+
+impl Score {
+    pub fn new(value: f64) -> Self {
+        Score {value, higher_is_better: true}
+    }
+    pub fn passes(&self, threshold: f64) -> bool {
+        if self.higher_is_better {
+            self.value >= threshold
+        } else {
+            self.value <= threshold
+        }
+    }
+}
+
+/// The scoring step of the optimizers.
+pub trait QualityMetric: Send + Sync {
+    fn name(&self) -> &str;
+    fn score(&self, reference: &VideoBuffer, distorted: &VideoBuffer) -> Score;
+    /// `None` means the optimizers pick a threshold per image class, which
+    /// is only calibrated for VMAF.
+    fn default_threshold(&self) -> Option<f64> {
+        None
+    }
+    /// Whether scores are VMAF scores, i.e. the VMAF-calibrated heuristics
+    /// of the optimizers apply.
+    fn is_vmaf(&self) -> bool {
+        false
+    }
+}
+
+/// Metrics that score every frame on its own, sequences get the mean.
+pub trait FrameMetric: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn score_frame(&self, reference: &Yuv420P, distorted: &Yuv420P) -> f64;
+    fn score_images(&self, reference: &DynamicImage, distorted: &DynamicImage) -> f64 {
+        let reference = Yuv420P::from_image(reference).expect("to Yuv420P");
+        let distorted = Yuv420P::from_image(distorted).expect("to Yuv420P");
+        self.score_frame(&reference, &distorted)
+    }
+    fn higher_is_better(&self) -> bool {
+        true
+    }
+    fn default_threshold(&self) -> f64;
+}
+
+impl<T: FrameMetric> QualityMetric for T {
+    fn name(&self) -> &str {
+        FrameMetric::name(self)
+    }
+    fn score(&self, reference: &VideoBuffer, distorted: &VideoBuffer) -> Score {
+        let (reference, distorted) = (reference.as_frames(), distorted.as_frames());
+        assert!(reference.len() == distorted.len());
+        assert!(!reference.is_empty());
+        let total: f64 = reference
+            .par_iter()
+            .zip(distorted.par_iter())
+            .map(|(a, b)| self.score_frame(a, b))
+            .sum();
+        Score {
+            value: total / (reference.len() as f64),
+            higher_is_better: self.higher_is_better(),
+        }
+    }
+    fn default_threshold(&self) -> Option<f64> {
+        Some(FrameMetric::default_threshold(self))
+    }
+}
+
+/// The threshold an optimizer should use, given its own (VMAF) threshold for
+/// the image class.
+pub fn resolve_threshold(
+    metric: &dyn QualityMetric,
+    threshold: Option<f64>,
+    class_threshold: f64,
+) -> f64 {
+    threshold
+        .or(metric.default_threshold())
+        .unwrap_or(class_threshold)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// PSNR
+///////////////////////////////////////////////////////////////////////////////
+
+/// Identical inputs have an infinite PSNR, this is reported instead.
+pub const MAX_PSNR: f64 = 100.0;
+
+fn mse(a: &[u8], b: &[u8]) -> f64 {
+    assert!(a.len() == b.len());
+    let sum: f64 = a
+        .iter()
+        .zip(b.iter())
+        .map(|(a, b)| {
+            let diff = (*a as f64) - (*b as f64);
+            diff * diff
+        })
+        .sum();
+    sum / (a.len().max(1) as f64)
+}
+
+/// PSNR over all planes, with luma weighted 6:1:1 against chroma.
+pub struct Psnr;
+
+impl FrameMetric for Psnr {
+    fn name(&self) -> &'static str {
+        "psnr"
+    }
+    fn score_frame(&self, reference: &Yuv420P, distorted: &Yuv420P) -> f64 {
+        assert!(reference.dimensions() == distorted.dimensions());
+        let mse = {
+            let y = mse(reference.y(), distorted.y());
+            let u = mse(reference.u(), distorted.u());
+            let v = mse(reference.v(), distorted.v());
+            (6.0 * y + u + v) / 8.0
+        };
+        if mse == 0.0 {
+            return MAX_PSNR;
+        }
+        (10.0 * ((255.0 * 255.0) / mse).log10()).min(MAX_PSNR)
+    }
+    fn default_threshold(&self) -> f64 {
+        40.0
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// SSIM
+///////////////////////////////////////////////////////////////////////////////
+
+/// Side length of the Gaussian window, as in the original SSIM paper.
+const SSIM_WINDOW: usize = 11;
+const SSIM_SIGMA: f32 = 1.5;
+const SSIM_C1: f32 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f32 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Weights of the five MS-SSIM scales, finest first.
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// A single channel, as floats.
+#[derive(Clone)]
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn luma(source: &Yuv420P) -> Self {
+        Plane {
+            width: source.width as usize,
+            height: source.height as usize,
+            data: source.y().iter().map(|x| *x as f32).collect(),
+        }
+    }
+    fn map2<F: Fn(f32, f32) -> f32>(&self, other: &Plane, f: F) -> Plane {
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: self.data.iter().zip(other.data.iter()).map(|(a, b)| f(*a, *b)).collect(),
+        }
+    }
+    /// Separable Gaussian filter, only keeping the fully covered pixels.
+    fn blur(&self) -> Plane {
+        let kernel = {
+            let radius = (SSIM_WINDOW / 2) as f32;
+            let xs = (0 .. SSIM_WINDOW)
+                .map(|i| {
+                    let x = i as f32 - radius;
+                    (-(x * x) / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp()
+                })
+                .collect::<Vec<_>>();
+            let total: f32 = xs.iter().sum();
+            xs.into_iter().map(|x| x / total).collect::<Vec<_>>()
+        };
+        let width = self.width - SSIM_WINDOW + 1;
+        let height = self.height - SSIM_WINDOW + 1;
+        // HORIZONTAL
+        let horizontal = self.data
+            .par_chunks(self.width)
+            .flat_map(|row| {
+                (0 .. width)
+                    .map(|x| kernel.iter().enumerate().map(|(k, w)| w * row[x + k]).sum::<f32>())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        // VERTICAL
+        let data = (0 .. height)
+            .into_par_iter()
+            .flat_map(|y| {
+                (0 .. width)
+                    .map(|x| {
+                        kernel
+                            .iter()
+                            .enumerate()
+                            .map(|(k, w)| w * horizontal[(y + k) * width + x])
+                            .sum::<f32>()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        Plane {width, height, data}
+    }
+    /// Halves both dimensions, averaging 2x2 blocks.
+    fn downsample(&self) -> Plane {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0 .. height {
+            for x in 0 .. width {
+                let ix = |dx: usize, dy: usize| self.data[(y * 2 + dy) * self.width + x * 2 + dx];
+                data.push((ix(0, 0) + ix(1, 0) + ix(0, 1) + ix(1, 1)) / 4.0);
+            }
+        }
+        Plane {width, height, data}
+    }
+}
+
+/// Mean luminance and contrast-structure terms, i.e. SSIM is their product.
+fn ssim_terms(a: &Plane, b: &Plane) -> (f64, f64) {
+    // IMAGES SMALLER THAN THE WINDOW ARE A SINGLE WINDOW, UNWEIGHTED
+    let fits = a.width >= SSIM_WINDOW && a.height >= SSIM_WINDOW;
+    let local = |x: Plane| -> Plane {
+        if fits {
+            return x.blur();
+        }
+        let mean = x.data.iter().sum::<f32>() / (x.data.len().max(1) as f32);
+        Plane {width: 1, height: 1, data: vec![mean]}
+    };
+    let mu_a = local(a.clone());
+    let mu_b = local(b.clone());
+    let aa = local(a.map2(a, |x, y| x * y));
+    let bb = local(b.map2(b, |x, y| x * y));
+    let ab = local(a.map2(b, |x, y| x * y));
+    let total = mu_a.data.len() as f64;
+    let (mut luminance, mut contrast_structure) = (0.0, 0.0);
+    for i in 0 .. mu_a.data.len() {
+        let (ma, mb) = (mu_a.data[i], mu_b.data[i]);
+        let var_a = aa.data[i] - ma * ma;
+        let var_b = bb.data[i] - mb * mb;
+        let cov = ab.data[i] - ma * mb;
+        let l = (2.0 * ma * mb + SSIM_C1) / (ma * ma + mb * mb + SSIM_C1);
+        let cs = (2.0 * cov + SSIM_C2) / (var_a + var_b + SSIM_C2);
+        luminance = luminance + (l as f64);
+        contrast_structure = contrast_structure + (cs as f64);
+    }
+    (luminance / total, contrast_structure / total)
+}
+
+/// Luma SSIM with an 11x11 Gaussian window.
+pub struct Ssim;
+
+impl FrameMetric for Ssim {
+    fn name(&self) -> &'static str {
+        "ssim"
+    }
+    fn score_frame(&self, reference: &Yuv420P, distorted: &Yuv420P) -> f64 {
+        assert!(reference.dimensions() == distorted.dimensions());
+        let (l, cs) = ssim_terms(&Plane::luma(reference), &Plane::luma(distorted));
+        l * cs
+    }
+    fn default_threshold(&self) -> f64 {
+        0.98
+    }
+}
+
+/// Luma MS-SSIM. Small images use fewer scales, with the remaining weights
+/// renormalized.
+pub struct MsSsim;
+
+impl FrameMetric for MsSsim {
+    fn name(&self) -> &'static str {
+        "ms-ssim"
+    }
+    fn score_frame(&self, reference: &Yuv420P, distorted: &Yuv420P) -> f64 {
+        assert!(reference.dimensions() == distorted.dimensions());
+        let (mut a, mut b) = (Plane::luma(reference), Plane::luma(distorted));
+        let scales = MS_SSIM_WEIGHTS
+            .iter()
+            .scan(a.width.min(a.height), |size, _| {
+                let fits = *size >= SSIM_WINDOW;
+                *size = *size / 2;
+                Some(fits)
+            })
+            .take_while(|x| *x)
+            .count()
+            .max(1);
+        let total_weight: f64 = MS_SSIM_WEIGHTS[0 .. scales].iter().sum();
+        let mut result = 1.0;
+        for scale in 0 .. scales {
+            let (l, cs) = ssim_terms(&a, &b);
+            let weight = MS_SSIM_WEIGHTS[scale] / total_weight;
+            // NEGATIVE CONTRAST-STRUCTURE WOULD FLIP THE SIGN
+            result = result * cs.max(0.0).powf(weight);
+            if scale + 1 == scales {
+                result = result * l.max(0.0).powf(weight);
+            } else {
+                a = a.downsample();
+                b = b.downsample();
+            }
+        }
+        result
+    }
+    fn default_threshold(&self) -> f64 {
+        0.98
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// CIEDE2000
+///////////////////////////////////////////////////////////////////////////////
+
+fn srgb_to_lab(px: [u8; 3]) -> [f64; 3] {
+    let linear = |x: u8| {
+        let x = (x as f64) / 255.0;
+        if x <= 0.04045 {x / 12.92} else {((x + 0.055) / 1.055).powf(2.4)}
+    };
+    let (r, g, b) = (linear(px[0]), linear(px[1]), linear(px[2]));
+    // XYZ, NORMALIZED TO THE D65 WHITE POINT
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {t.cbrt()} else {(24389.0 / 27.0 * t + 16.0) / 116.0}
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// The CIEDE2000 color difference, see Sharma et al. (2005).
+pub fn delta_e_2000(lab1: [f64; 3], lab2: [f64; 3]) -> f64 {
+    let [l1, a1, b1] = lab1;
+    let [l2, a2, b2] = lab2;
+    let c_bar = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.0;
+    let g = 0.5 * (1.0 - (c_bar.powi(7) / (c_bar.powi(7) + 25f64.powi(7))).sqrt());
+    let (a1, a2) = (a1 * (1.0 + g), a2 * (1.0 + g));
+    let (c1, c2) = ((a1 * a1 + b1 * b1).sqrt(), (a2 * a2 + b2 * b2).sqrt());
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 {
+            return 0.0;
+        }
+        let h = b.atan2(a).to_degrees();
+        if h < 0.0 {h + 360.0} else {h}
+    };
+    let (h1, h2) = (hue(b1, a1), hue(b2, a2));
+    // DIFFERENCES
+    let delta_l = l2 - l1;
+    let delta_c = c2 - c1;
+    let delta_h = {
+        let dh = if c1 * c2 == 0.0 {
+            0.0
+        } else if (h2 - h1).abs() <= 180.0 {
+            h2 - h1
+        } else if h2 - h1 > 180.0 {
+            h2 - h1 - 360.0
+        } else {
+            h2 - h1 + 360.0
+        };
+        2.0 * (c1 * c2).sqrt() * (dh / 2.0).to_radians().sin()
+    };
+    // MEANS
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    let h_bar = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+    // WEIGHTS
+    let t = 1.0
+        - 0.17 * (h_bar - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar).to_radians().cos()
+        + 0.32 * (3.0 * h_bar + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar - 63.0).to_radians().cos();
+    let s_l = 1.0 + (0.015 * (l_bar - 50.0).powi(2)) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar;
+    let s_h = 1.0 + 0.015 * c_bar * t;
+    let r_t = {
+        let delta_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+        let r_c = 2.0 * (c_bar.powi(7) / (c_bar.powi(7) + 25f64.powi(7))).sqrt();
+        -r_c * (2.0 * delta_theta).to_radians().sin()
+    };
+    // DONE
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_h / s_h);
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
+/// Mean CIEDE2000 difference over all pixels, lower is better.
+pub struct Ciede2000;
+
+impl Ciede2000 {
+    fn mean_difference(reference: &DynamicImage, distorted: &DynamicImage) -> f64 {
+        assert!(reference.dimensions() == distorted.dimensions());
+        let (width, height) = reference.dimensions();
+        let reference = reference.to_rgb().into_raw();
+        let distorted = distorted.to_rgb().into_raw();
+        let total: f64 = reference
+            .par_chunks(3)
+            .zip(distorted.par_chunks(3))
+            .map(|(a, b)| {
+                delta_e_2000(srgb_to_lab([a[0], a[1], a[2]]), srgb_to_lab([b[0], b[1], b[2]]))
+            })
+            .sum();
+        total / ((width * height).max(1) as f64)
+    }
+}
+
+impl FrameMetric for Ciede2000 {
+    fn name(&self) -> &'static str {
+        "ciede2000"
+    }
+    fn score_frame(&self, reference: &Yuv420P, distorted: &Yuv420P) -> f64 {
+        Ciede2000::mean_difference(&reference.to_rgba_image(), &distorted.to_rgba_image())
+    }
+    fn score_images(&self, reference: &DynamicImage, distorted: &DynamicImage) -> f64 {
+        Ciede2000::mean_difference(reference, distorted)
+    }
+    fn higher_is_better(&self) -> bool {
+        false
+    }
+    /// About one just noticeable difference.
+    fn default_threshold(&self) -> f64 {
+        1.0
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// VMAF
+///////////////////////////////////////////////////////////////////////////////
+
+pub struct Vmaf(pub VmafOptions);
+
+impl QualityMetric for Vmaf {
+    fn name(&self) -> &str {
+        "vmaf"
+    }
+    fn is_vmaf(&self) -> bool {
+        true
+    }
+    /// Whole sequences, so the motion feature sees consecutive frames.
+    fn score(&self, reference: &VideoBuffer, distorted: &VideoBuffer) -> Score {
+        Score::new(vmaf::get_score(reference, distorted, &self.0))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// WRAPPERS
+///////////////////////////////////////////////////////////////////////////////
+
+fn fingerprint(source: &VideoBuffer) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.dimensions().hash(&mut hasher);
+    for frame in source.as_frames() {
+        frame.data.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Remembers scores by content, e.g. the quality searches probe some
+/// parameters more than once.
+pub struct Cached {
+    metric: Arc<dyn QualityMetric>,
+    cache: Mutex<HashMap<(u64, u64), Score>>,
+}
+
+impl Cached {
+    pub fn new(metric: Arc<dyn QualityMetric>) -> Self {
+        Cached {
+            metric,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl QualityMetric for Cached {
+    fn name(&self) -> &str {
+        self.metric.name()
+    }
+    fn score(&self, reference: &VideoBuffer, distorted: &VideoBuffer) -> Score {
+        let key = (fingerprint(reference), fingerprint(distorted));
+        if let Some(score) = self.cache.lock().expect("metric cache").get(&key) {
+            return *score;
+        }
+        // NOT LOCKED WHILE SCORING, SO PARALLEL PROBES DON'T SERIALIZE
+        let score = self.metric.score(reference, distorted);
+        self.cache.lock().expect("metric cache").insert(key, score);
+        score
+    }
+    fn default_threshold(&self) -> Option<f64> {
+        self.metric.default_threshold()
+    }
+    fn is_vmaf(&self) -> bool {
+        self.metric.is_vmaf()
+    }
+}
+
+/// Any function as a metric, e.g. a test double. Higher is better.
+pub struct FnMetric<F>(pub F);
+
+impl<F> QualityMetric for FnMetric<F>
+where
+    F: Fn(&VideoBuffer, &VideoBuffer) -> f64 + Send + Sync
+{
+    fn name(&self) -> &str {
+        "custom"
+    }
+    fn score(&self, reference: &VideoBuffer, distorted: &VideoBuffer) -> Score {
+        Score::new((self.0)(reference, distorted))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// SELECTION
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Metric {
+    Vmaf,
+    Psnr,
+    Ssim,
+    MsSsim,
+    Ciede2000,
+}
+
+impl Default for Metric {
+    fn default() -> Self {
+        Metric::Vmaf
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "vmaf" => Ok(Metric::Vmaf),
+            "psnr" => Ok(Metric::Psnr),
+            "ssim" => Ok(Metric::Ssim),
+            "ms-ssim" | "msssim" => Ok(Metric::MsSsim),
+            "ciede2000" | "de2000" => Ok(Metric::Ciede2000),
+            _ => {
+                Err(format!(
+                    "Unknown metric {}, expected one of vmaf, psnr, ssim, ms-ssim, or ciede2000",
+                    s,
+                ))
+            }
+        }
+    }
+}
+
+/// Which metric and threshold drive the optimizers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QualityOptions {
+    pub metric: Metric,
+    /// Defaults to the metric's own threshold, or the per-class thresholds
+    /// for VMAF.
+    pub threshold: Option<f64>,
+    /// Only used when `metric` is `Metric::Vmaf`.
+    pub vmaf: VmafOptions,
+}
+
+impl Default for QualityOptions {
+    fn default() -> Self {
+        QualityOptions {
+            metric: Metric::Vmaf,
+            threshold: None,
+            vmaf: VmafOptions::default(),
+        }
+    }
+}
+
+impl QualityOptions {
+    pub fn build_metric(&self) -> Arc<dyn QualityMetric> {
+        match self.metric {
+            Metric::Vmaf => Arc::new(Vmaf(self.vmaf.clone())),
+            Metric::Psnr => Arc::new(Psnr),
+            Metric::Ssim => Arc::new(Ssim),
+            Metric::MsSsim => Arc::new(MsSsim),
+            Metric::Ciede2000 => Arc::new(Ciede2000),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::testing::test_frame;
+
+    /// Luma from `luma(x, y)`, neutral chroma.
+    fn synthetic_frame<F: Fn(u32, u32) -> u8>(width: u32, height: u32, luma: F) -> Yuv420P {
+        let mut data = Vec::new();
+        for y in 0 .. height {
+            for x in 0 .. width {
+                data.push(luma(x, y));
+            }
+        }
+        data.resize((width * height + 2 * (width * height / 4)) as usize, 128);
+        Yuv420P {width, height, data}
+    }
+
+    #[test]
+    fn test_identical_frames() {
+        let frame = test_frame();
+        assert!(Psnr.score_frame(&frame, &frame) == MAX_PSNR);
+        assert!((Ssim.score_frame(&frame, &frame) - 1.0).abs() < 1e-4);
+        assert!((MsSsim.score_frame(&frame, &frame) - 1.0).abs() < 1e-4);
+        assert!(Ciede2000.score_frame(&frame, &frame) == 0.0);
+    }
+
+    /// Sharma, Wu & Dalal (2005), "The CIEDE2000 color-difference formula:
+    /// implementation notes, supplementary test data, and mathematical
+    /// observations", table 1.
+    #[test]
+    fn test_delta_e_2000_reference_pairs() {
+        let pairs: [([f64; 3], [f64; 3], f64); 34] = [
+            ([50.0000, 2.6772, -79.7751], [50.0000, 0.0000, -82.7485], 2.0425),
+            ([50.0000, 3.1571, -77.2803], [50.0000, 0.0000, -82.7485], 2.8615),
+            ([50.0000, 2.8361, -74.0200], [50.0000, 0.0000, -82.7485], 3.4412),
+            ([50.0000, -1.3802, -84.2814], [50.0000, 0.0000, -82.7485], 1.0000),
+            ([50.0000, -1.1848, -84.8006], [50.0000, 0.0000, -82.7485], 1.0000),
+            ([50.0000, -0.9009, -85.5211], [50.0000, 0.0000, -82.7485], 1.0000),
+            ([50.0000, 0.0000, 0.0000], [50.0000, -1.0000, 2.0000], 2.3669),
+            ([50.0000, -1.0000, 2.0000], [50.0000, 0.0000, 0.0000], 2.3669),
+            ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0009], 7.1792),
+            ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0010], 7.1792),
+            ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0011], 7.2195),
+            ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0012], 7.2195),
+            ([50.0000, -0.0010, 2.4900], [50.0000, 0.0009, -2.4900], 4.8045),
+            ([50.0000, -0.0010, 2.4900], [50.0000, 0.0010, -2.4900], 4.8045),
+            ([50.0000, -0.0010, 2.4900], [50.0000, 0.0011, -2.4900], 4.7461),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 0.0000, -2.5000], 4.3065),
+            ([50.0000, 2.5000, 0.0000], [73.0000, 25.0000, -18.0000], 27.1492),
+            ([50.0000, 2.5000, 0.0000], [61.0000, -5.0000, 29.0000], 22.8977),
+            ([50.0000, 2.5000, 0.0000], [56.0000, -27.0000, -3.0000], 31.9030),
+            ([50.0000, 2.5000, 0.0000], [58.0000, 24.0000, 15.0000], 19.4535),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 3.1736, 0.5854], 1.0000),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 3.2972, 0.0000], 1.0000),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 1.8634, 0.5757], 1.0000),
+            ([50.0000, 2.5000, 0.0000], [50.0000, 3.2592, 0.3350], 1.0000),
+            ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+            ([63.0109, -31.0961, -5.8663], [62.8187, -29.7946, -4.0864], 1.2630),
+            ([61.2901, 3.7196, -5.3901], [61.4292, 2.2480, -4.9620], 1.8731),
+            ([35.0831, -44.1164, 3.7933], [35.0232, -40.0716, 1.5901], 1.8645),
+            ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 2.0373),
+            ([36.4612, 47.8580, 18.3852], [36.2715, 50.5065, 21.2231], 1.4146),
+            ([90.8027, -2.0831, 1.4410], [91.1528, -1.6435, 0.0447], 1.4441),
+            ([90.9257, -0.5406, -0.9208], [88.6381, -0.8985, -0.7239], 1.5381),
+            ([6.7747, -0.2908, -2.4247], [5.8714, -0.0985, -2.2286], 0.6377),
+            ([2.0776, 0.0795, -1.1350], [0.9033, -0.0636, -0.5514], 0.9082),
+        ];
+        for (lab1, lab2, expected) in pairs.iter() {
+            // THE TABLE IS ROUNDED TO FOUR DECIMALS
+            assert!((delta_e_2000(*lab1, *lab2) - expected).abs() < 1e-4);
+            assert!((delta_e_2000(*lab2, *lab1) - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_psnr_constant_offset() {
+        // MSE IS 25 FOR LUMA AND 0 FOR CHROMA, I.E. (6 * 25) / 8 OVERALL
+        let reference = synthetic_frame(64, 64, |x, y| (20 + (x * 3 + y) % 200) as u8);
+        let distorted = synthetic_frame(64, 64, |x, y| (25 + (x * 3 + y) % 200) as u8);
+        let expected = 10.0 * ((255.0 * 255.0) / (6.0 * 25.0 / 8.0) as f64).log10();
+        assert!((Psnr.score_frame(&reference, &distorted) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ssim_flat_offset() {
+        // NO VARIANCE, SO THE CONTRAST-STRUCTURE TERM IS 1 AND SSIM IS
+        // THE LUMINANCE TERM OF THE TWO MEANS
+        let reference = synthetic_frame(64, 64, |_, _| 100);
+        let distorted = synthetic_frame(64, 64, |_, _| 110);
+        let c1 = (0.01 * 255.0) * (0.01 * 255.0);
+        let expected = (2.0 * 100.0 * 110.0 + c1) / (100.0 * 100.0 + 110.0 * 110.0 + c1);
+        // THE WINDOWS ARE ACCUMULATED IN F32
+        assert!((Ssim.score_frame(&reference, &distorted) - expected).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_cached_metric() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let metric = {
+            let calls = calls.clone();
+            FnMetric(move |_: &VideoBuffer, _: &VideoBuffer| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                50.0
+            })
+        };
+        let metric = Cached::new(Arc::new(metric));
+        let source = VideoBuffer::singleton(test_frame());
+        for _ in 0 .. 3 {
+            assert!(metric.score(&source, &source) == Score::new(50.0));
+        }
+        assert!(calls.load(Ordering::SeqCst) == 1);
+    }
+}
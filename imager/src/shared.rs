@@ -0,0 +1,311 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//! Helpers that `imager-video` compiles as well (it includes this file with
+//! `#[path]`, the two crates can't depend on each other). Keep this to
+//! dependencies both crates have, i.e. `std`, `libc`, `image`, `rand`,
+//! `serde` and `serde_json`.
+//!
+//! The VMAF sampling and report code lives here, so the two VMAF pipelines
+//! (`imager::vmaf` and `imager_video::tool::vmaf`) share one copy of it.
+use std::collections::BTreeSet;
+use image::{DynamicImage, GenericImageView};
+use rand::{Rng, SeedableRng};
+use serde::{Serialize, Deserialize};
+
+///////////////////////////////////////////////////////////////////////////////
+// RESOLUTION
+///////////////////////////////////////////////////////////////////////////////
+
+/// Crops (never rescales) odd widths or heights by one pixel, YUV 4:2:0
+/// needs even dimensions.
+pub fn ensure_even_reslution(source: &DynamicImage) -> DynamicImage {
+    let (width, height) = source.dimensions();
+    // ENSURE EVEN
+    let even_width = (width % 2) == 0;
+    let even_height = (height % 2) == 0;
+    if (!even_width) || (!even_height) {
+        let new_width = {
+            if !even_width {
+                width - 1
+            } else {
+                width
+            }
+        };
+        let new_height = {
+            if !even_height {
+                height - 1
+            } else {
+                height
+            }
+        };
+        let new_image = source
+            .clone()
+            .crop(0, 0, new_width, new_height);
+        new_image
+    } else {
+        source.clone()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// GIF
+///////////////////////////////////////////////////////////////////////////////
+
+/// Browsers show frames with a delay of 10ms or less for 100ms.
+const MIN_GIF_DELAY: u32 = 20;
+const DEFAULT_GIF_DELAY: u32 = 100;
+
+/// The delay (in milliseconds) a GIF frame is actually shown for.
+pub fn gif_delay(delay: u32) -> u32 {
+    if delay < MIN_GIF_DELAY {DEFAULT_GIF_DELAY} else {delay}
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// VMAF LOCALE
+///////////////////////////////////////////////////////////////////////////////
+
+/// Every `compute_vmaf` call loads its model through libsvm's
+/// `svm_load_model`, which parses it with `strtod` between two
+/// `setlocale(LC_ALL, ..)` calls, i.e. it switches the process-global
+/// locale. A thread with its own locale (`uselocale`) doesn't see those
+/// switches, so scoring threads hold one of these for the length of the
+/// `compute_vmaf` call instead of a global lock.
+pub struct ThreadLocale {
+    locale: libc::locale_t,
+    previous: libc::locale_t,
+}
+
+impl ThreadLocale {
+    /// Switches the calling thread to its own "C" locale, until dropped.
+    pub fn c() -> Self {
+        unsafe {
+            let name = b"C\0".as_ptr() as *const libc::c_char;
+            let locale = libc::newlocale(libc::LC_ALL_MASK, name, std::ptr::null_mut());
+            assert!(!locale.is_null(), "newlocale failed");
+            let previous = libc::uselocale(locale);
+            ThreadLocale {locale, previous}
+        }
+    }
+}
+
+impl Drop for ThreadLocale {
+    fn drop(&mut self) {
+        unsafe {
+            libc::uselocale(self.previous);
+            libc::freelocale(self.locale);
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// VMAF SAMPLING
+///////////////////////////////////////////////////////////////////////////////
+
+/// Which frames get scored, for long videos where scoring everything is too
+/// slow.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Sampling {
+    All,
+    /// Frames `start .. end`.
+    Range {start: usize, end: usize},
+    /// Every n-th frame. Scored as one sequence, so the motion feature
+    /// compares each sampled frame to the previous sampled frame.
+    EveryNth(usize),
+    /// Random windows of consecutive frames, each window is scored on its
+    /// own. Seeded, so reruns pick the same windows.
+    RandomWindows {count: usize, length: usize, seed: u64},
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling::All
+    }
+}
+
+impl Sampling {
+    /// Frame indices (below `total`), grouped into the sequences that are
+    /// scored separately. Empty if nothing is picked.
+    pub fn runs(&self, total: usize) -> Vec<Vec<usize>> {
+        if total == 0 {
+            return Vec::new();
+        }
+        let runs = match self.clone() {
+            Sampling::All => vec![(0 .. total).collect()],
+            Sampling::Range {start, end} => {
+                // CLAMPED TO THE STREAM, E.G. `start >= total` PICKS NOTHING
+                let end = end.min(total);
+                let start = start.min(end);
+                vec![(start .. end).collect()]
+            }
+            Sampling::EveryNth(n) => vec![(0 .. total).step_by(n.max(1)).collect()],
+            Sampling::RandomWindows {count, length, seed} => {
+                let length = length.max(1).min(total);
+                let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+                let starts = (0 .. count)
+                    .map(|_| rng.gen_range(0, total - length + 1))
+                    .collect::<BTreeSet<_>>();
+                // OVERLAPPING WINDOWS ARE MERGED
+                let mut runs = Vec::<Vec<usize>>::new();
+                for start in starts {
+                    match runs.last_mut() {
+                        Some(run) if *run.last().expect("non-empty run") + 1 >= start => {
+                            let next = run.last().expect("non-empty run") + 1;
+                            run.extend(next .. start + length);
+                        }
+                        _ => runs.push((start .. start + length).collect()),
+                    }
+                }
+                runs
+            }
+        };
+        runs.into_iter().filter(|x| !x.is_empty()).collect()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// VMAF POOLING
+///////////////////////////////////////////////////////////////////////////////
+
+/// How per-frame scores are aggregated into `VmafReport::pooled`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PoolMethod {
+    Mean,
+    /// Penalizes low outliers more than the mean does.
+    Harmonic,
+    Min,
+}
+
+/// Which aggregate the optimizers compare against their thresholds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Gate {
+    /// See `PoolMethod`.
+    Pooled,
+    /// The worst frame.
+    Min,
+    /// Nearest-rank percentile, e.g. `Percentile(5.0)` tolerates the worst
+    /// five percent of frames.
+    Percentile(f64),
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// VMAF REPORTS
+///////////////////////////////////////////////////////////////////////////////
+
+/// The elementary features the VMAF model is fed with, per frame.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Features {
+    pub vif_scale0: f64,
+    pub vif_scale1: f64,
+    pub vif_scale2: f64,
+    pub vif_scale3: f64,
+    pub adm2: f64,
+    pub motion2: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfidenceInterval {
+    pub low: f64,
+    pub high: f64,
+    pub stddev: f64,
+}
+
+/// A single row of libvmaf's log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameMetrics {
+    pub vmaf: f64,
+    pub features: Features,
+    pub confidence: Option<ConfidenceInterval>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VmafReport {
+    /// Indices of the scored frames, within the source streams.
+    pub frames: Vec<usize>,
+    /// Per-frame scores, in the same order.
+    pub scores: Vec<f64>,
+    /// Per-frame features and confidence intervals, in the same order.
+    pub metrics: Vec<FrameMetrics>,
+    pub pool: PoolMethod,
+    /// Aggregate of `scores` as per `pool`.
+    pub pooled: f64,
+    pub mean: f64,
+    pub harmonic_mean: f64,
+    pub min: f64,
+    /// 1st percentile, i.e. (almost) the worst frame.
+    pub percentile_1: f64,
+}
+
+impl VmafReport {
+    /// `None` if nothing was scored, or the lengths don't match.
+    pub fn new(frames: Vec<usize>, metrics: Vec<FrameMetrics>, pool: PoolMethod) -> Option<Self> {
+        if metrics.is_empty() || frames.len() != metrics.len() {
+            return None;
+        }
+        let scores = metrics.iter().map(|x| x.vmaf).collect::<Vec<_>>();
+        let total = scores.len() as f64;
+        let mean = scores.iter().sum::<f64>() / total;
+        // LIBVMAF'S VARIANT, OFFSET BY ONE TO TOLERATE ZERO SCORES
+        let harmonic_mean = total / scores.iter().map(|x| 1.0 / (x + 1.0)).sum::<f64>() - 1.0;
+        let min = scores.iter().cloned().fold(std::f64::INFINITY, f64::min);
+        let percentile_1 = percentile(&scores, 1.0);
+        // POOLED OVER ALL RUNS, SO NOT LEFT TO LIBVMAF
+        let pooled = match pool {
+            PoolMethod::Mean => mean,
+            PoolMethod::Harmonic => harmonic_mean,
+            PoolMethod::Min => min,
+        };
+        Some(VmafReport {frames, scores, metrics, pool, pooled, mean, harmonic_mean, min, percentile_1})
+    }
+    /// The score to compare against a threshold.
+    pub fn gated(&self, gate: Gate) -> f64 {
+        match gate {
+            Gate::Pooled => self.pooled,
+            Gate::Min => self.min,
+            Gate::Percentile(p) => percentile(&self.scores, p),
+        }
+    }
+}
+
+/// Nearest-rank percentile.
+pub fn percentile(scores: &[f64], p: f64) -> f64 {
+    let mut sorted = scores.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).expect("not NaN"));
+    let rank = ((p / 100.0) * (sorted.len() as f64)).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+/// Per-frame metrics from libvmaf's JSON log.
+pub fn parse_log(log: &str) -> Vec<FrameMetrics> {
+    let log: serde_json::Value = serde_json::from_str(log).expect("vmaf json log");
+    let parse_frame = |frame: &serde_json::Value| {
+        let metric = |name: &str| frame["metrics"][name].as_f64();
+        let feature = |name: &str| metric(name).expect("vmaf frame feature");
+        let confidence = match (metric("vmaf_ci95_low"), metric("vmaf_ci95_high")) {
+            (Some(low), Some(high)) => Some(ConfidenceInterval {
+                low,
+                high,
+                stddev: metric("vmaf_stddev").unwrap_or(0.0),
+            }),
+            _ => None,
+        };
+        FrameMetrics {
+            vmaf: feature("vmaf"),
+            features: Features {
+                vif_scale0: feature("vif_scale0"),
+                vif_scale1: feature("vif_scale1"),
+                vif_scale2: feature("vif_scale2"),
+                vif_scale3: feature("vif_scale3"),
+                adm2: feature("adm2"),
+                motion2: feature("motion2"),
+            },
+            confidence,
+        }
+    };
+    log["frames"]
+        .as_array()
+        .expect("vmaf log frames")
+        .iter()
+        .map(parse_frame)
+        .collect()
+}
@@ -0,0 +1,19 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//! Fixtures shared by the unit tests.
+use image::DynamicImage;
+
+use crate::data::Yuv420P;
+
+/// A small photo, so the optimizers stay fast.
+pub fn test_image() -> DynamicImage {
+    ::image::load_from_memory(include_bytes!("../assets/test/1.jpeg"))
+        .expect("load test image")
+        .resize_exact(64, 64, ::image::FilterType::Triangle)
+}
+
+/// `test_image` as a single frame.
+pub fn test_frame() -> Yuv420P {
+    Yuv420P::from_image(&test_image()).expect("to Yuv420P")
+}
@@ -2,29 +2,164 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use std::path::PathBuf;
-use std::sync::{Mutex, MutexGuard};
+use std::str::FromStr;
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int};
 use libc::{size_t, c_float, c_void};
-use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
 
 use crate::data::{Yuv420P, VideoBuffer};
+pub use crate::shared::{
+    Sampling,
+    PoolMethod,
+    Gate,
+    Features,
+    ConfidenceInterval,
+    FrameMetrics,
+    VmafReport,
+    percentile,
+};
+use crate::shared::{parse_log, ThreadLocale};
 
 
 ///////////////////////////////////////////////////////////////////////////////
 // VMAF CONTEXT
 ///////////////////////////////////////////////////////////////////////////////
 
+/// One per `compute_vmaf` call, which also loads its own model and writes
+/// its own log file. So concurrent calls don't share any state, see
+/// `ThreadLocale` for the locale.
 struct Context<'a> {
-    stream1: &'a mut VideoBuffer,
-    stream2: &'a mut VideoBuffer,
+    frames1: &'a [Yuv420P],
+    frames2: &'a [Yuv420P],
+    cursor: usize,
     frames_set: bool,
 }
 
-lazy_static! {
-    static ref VMAF_LOCK: Mutex<()> = {
-        Mutex::new(())
-    };
+///////////////////////////////////////////////////////////////////////////////
+// OPTIONS
+///////////////////////////////////////////////////////////////////////////////
+
+/// The bundled libvmaf is 1.x, which only reads `.pkl` models (each with
+/// its `.pkl.model` file next to it).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum VmafModel {
+    /// Trained for 4K TVs, the strictest of the bundled models.
+    FourK,
+    /// Trained for 1080p TVs.
+    Default,
+    /// The 1080p model, adjusted for viewing on phones.
+    Phone,
+    /// Path to a `.pkl` model file.
+    Custom(PathBuf),
+}
+
+impl Default for VmafModel {
+    fn default() -> Self {
+        VmafModel::FourK
+    }
+}
+
+impl FromStr for VmafModel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "4k" => Ok(VmafModel::FourK),
+            "default" | "1080p" => Ok(VmafModel::Default),
+            "phone" => Ok(VmafModel::Phone),
+            _ => VmafModel::custom(s),
+        }
+    }
+}
+
+impl VmafModel {
+    /// Checks the model file up front, rather than failing inside libvmaf.
+    pub fn custom<P: Into<PathBuf>>(path: P) -> Result<Self, String> {
+        let path = path.into();
+        let extension = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_lowercase());
+        match extension.as_ref().map(|x| x.as_str()) {
+            Some("pkl") => (),
+            Some("json") => {
+                return Err(format!(
+                    "JSON models need libvmaf 2.x, only `.pkl` models are supported: {:?}",
+                    path,
+                ));
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown VMAF model, expected `4k`, `default`, `phone`, or a `.pkl` file: {:?}",
+                    path,
+                ));
+            }
+        }
+        if !path.exists() {
+            return Err(format!("Missing VMAF model file: {:?}", path));
+        }
+        Ok(VmafModel::Custom(path))
+    }
+    fn model_path(&self) -> PathBuf {
+        match self {
+            VmafModel::FourK => vmaf_sys::extras::get_4k_model_path(),
+            VmafModel::Default | VmafModel::Phone => vmaf_sys::extras::get_def_model_path(),
+            VmafModel::Custom(path) => path.clone(),
+        }
+    }
+    fn is_phone(&self) -> bool {
+        match self {
+            VmafModel::Phone => true,
+            _ => false
+        }
+    }
+    /// Bootstrapped models (e.g. `vmaf_4k_rb_v0.6.2`) store their model type
+    /// as plain text in the `.pkl` file. None of the bundled ones are.
+    pub fn is_bootstrapped(&self) -> bool {
+        match self {
+            VmafModel::Custom(path) => {
+                std::fs::read(path)
+                    .map(|x| String::from_utf8_lossy(&x).contains("BOOTSTRAP"))
+                    .unwrap_or(false)
+            }
+            _ => false
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VmafOptions {
+    pub model: VmafModel,
+    pub pool: PoolMethod,
+    pub gate: Gate,
+    /// Per-frame 95% confidence intervals. Only bootstrapped models (e.g.
+    /// `vmaf_4k_rb_v0.6.2`) support this, libvmaf fails on the others.
+    pub confidence_interval: bool,
+}
+
+impl VmafOptions {
+    /// Catches combinations libvmaf would fail on.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.confidence_interval && !self.model.is_bootstrapped() {
+            return Err(format!(
+                "Confidence intervals need a bootstrapped VMAF model (e.g. `vmaf_4k_rb_v0.6.2.pkl`), got: {:?}",
+                self.model,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for VmafOptions {
+    fn default() -> Self {
+        VmafOptions {
+            model: VmafModel::default(),
+            pool: PoolMethod::Mean,
+            gate: Gate::Pooled,
+            confidence_interval: false,
+        }
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -69,7 +204,9 @@ unsafe extern "C" fn read_frame(
     }
 
     // NEXT FRAME OR DONE
-    match (vmaf_ctx.stream1.next(), vmaf_ctx.stream2.next()) {
+    let cursor = vmaf_ctx.cursor;
+    vmaf_ctx.cursor = cursor + 1;
+    match (vmaf_ctx.frames1.get(cursor), vmaf_ctx.frames2.get(cursor)) {
         (Some(frame1), Some(frame2)) => {
             fill_vmaf_buffer(source1_out, out_stride, &frame1);
             fill_vmaf_buffer(source2_out, out_stride, &frame2);
@@ -91,40 +228,59 @@ unsafe extern "C" fn read_frame(
 // VMAF PIPELINE
 ///////////////////////////////////////////////////////////////////////////////
 
-pub unsafe fn vmaf_controller<'a>(stream1: &'a mut VideoBuffer, stream2: &'a mut VideoBuffer) -> f64 {
+/// Scores frames pairwise, returning the per-frame metrics. `None` if
+/// libvmaf's log can't be read back.
+pub unsafe fn vmaf_controller(
+    frames1: &[Yuv420P],
+    frames2: &[Yuv420P],
+    options: &VmafOptions,
+) -> Option<Vec<FrameMetrics>> {
     // CHECKS
-    assert!(stream1.dimensions() == stream2.dimensions());
+    assert!(frames1.len() == frames2.len());
+    assert!(!frames1.is_empty());
+    let (width, height) = frames1[0].dimensions();
+    assert!(frames1.iter().chain(frames2.iter()).all(|x| x.dimensions() == (width, height)));
 
     // INIT VMAF CONTEXT
-    let (width, height) = stream1.dimensions();
     let vmaf_ctx = Box::new(Context {
-        stream1: stream1,
-        stream2: stream2,
+        frames1,
+        frames2,
+        cursor: 0,
         frames_set: false
     });
     let vmaf_ctx = Box::into_raw(vmaf_ctx);
 
     // SETTINGS
     let mut vmaf_score = 0.0;
-    let model_path = vmaf_sys::extras::get_4k_model_path()
+    let model_path = options.model
+        .model_path()
         .to_str()
         .expect("PathBuf to str failed")
         .to_owned();
     let model_path = CString::new(model_path).expect("CString::new failed");
     let mut fmt = CString::new(String::from("yuv420p")).expect("CString::new failed");
-    let log_path: *mut c_char = std::ptr::null_mut();
-    let log_fmt: *mut c_char = std::ptr::null_mut();
+    // PER-FRAME SCORES ONLY COME THROUGH THE LOG FILE
+    let log_file = std::env::temp_dir().join(format!(
+        "imager-vmaf-{}-{:p}.json",
+        std::process::id(),
+        vmaf_ctx,
+    ));
+    let log_path = CString::new(log_file.to_str().expect("PathBuf to str failed")).expect("CString::new failed");
+    let log_fmt = CString::new("json").expect("CString::new failed");
     let disable_clip = 0;
     let disable_avx = 0;
     let enable_transform = 0;
-    let phone_model = 0;
+    let phone_model = if options.model.is_phone() {1} else {0};
     let do_psnr = 0;
     let do_ssim = 0;
     let do_ms_ssim = 0;
     let pool_method: *mut c_char = std::ptr::null_mut();
     let n_thread = 1;
     let n_subsample = 1;
-    let enable_conf_interval = 0;
+    let enable_conf_interval = if options.confidence_interval {1} else {0};
+
+    // THIS THREAD ONLY
+    let locale = ThreadLocale::c();
 
     // GO!
     let status = vmaf_sys::compute_vmaf(
@@ -135,8 +291,8 @@ pub unsafe fn vmaf_controller<'a>(stream1: &'a mut VideoBuffer, stream2: &'a mut
         Some(read_frame),
         vmaf_ctx as *mut libc::c_void,
         model_path.as_ptr() as *mut c_char,
-        log_path,
-        log_fmt,
+        log_path.as_ptr() as *mut c_char,
+        log_fmt.as_ptr() as *mut c_char,
         disable_clip,
         disable_avx,
         enable_transform,
@@ -150,28 +306,60 @@ pub unsafe fn vmaf_controller<'a>(stream1: &'a mut VideoBuffer, stream2: &'a mut
         enable_conf_interval
     );
 
+    // RESTORE
+    std::mem::drop(locale);
+
     // CHECK
     assert!(status == 0);
 
     // CLEANUP
     let mut vmaf_ctx = Box::from_raw(vmaf_ctx);
     std::mem::drop(vmaf_ctx);
+    let log = std::fs::read_to_string(&log_file).ok();
+    let _ = std::fs::remove_file(&log_file);
 
     // DONE
-    vmaf_score
+    let metrics = parse_log(&log?);
+    assert!(metrics.len() == frames1.len());
+    Some(metrics)
 }
 
-pub fn get_report(stream1: &VideoBuffer, stream2: &VideoBuffer) -> f64 {
+/// Score the sampled frames of both streams. Streams of different lengths
+/// are only compared up to the shorter one. `None` if the sampling picks no
+/// frames, or if libvmaf's log can't be read back.
+pub fn get_report_sampled(
+    stream1: &VideoBuffer,
+    stream2: &VideoBuffer,
+    sampling: &Sampling,
+    options: &VmafOptions,
+) -> Option<VmafReport> {
     // SETUP
-    let mut stream1 = stream1.as_fresh_cursor();
-    let mut stream2 = stream2.as_fresh_cursor();
-    assert!(stream1.as_frames().len() == stream2.as_frames().len());
-    // LOCK
-    let lock = VMAF_LOCK.lock().expect("failed to lock vmaf work");
+    assert!(stream1.dimensions() == stream2.dimensions());
+    let total = stream1.as_frames().len().min(stream2.as_frames().len());
+    let mut frames = Vec::<usize>::new();
+    let mut metrics = Vec::<FrameMetrics>::new();
     // GO!
-    let score = unsafe {vmaf_controller(&mut stream1, &mut stream2)};
-    // UNLOCK
-    std::mem::drop(lock);
+    for run in sampling.runs(total) {
+        let pick = |xs: &[Yuv420P]| run
+            .iter()
+            .map(|ix| xs[*ix].clone())
+            .collect::<Vec<_>>();
+        let (frames1, frames2) = (pick(stream1.as_frames()), pick(stream2.as_frames()));
+        metrics.extend(unsafe {vmaf_controller(&frames1, &frames2, options)}?);
+        frames.extend(run);
+    }
     // DONE
-    score
-}
\ No newline at end of file
+    VmafReport::new(frames, metrics, options.pool)
+}
+
+/// Every frame, gated as per the given options. Empty streams score zero.
+pub fn get_score(stream1: &VideoBuffer, stream2: &VideoBuffer, options: &VmafOptions) -> f64 {
+    assert!(stream1.as_frames().len() == stream2.as_frames().len());
+    get_report_sampled(stream1, stream2, &Sampling::All, options)
+        .map(|x| x.gated(options.gate))
+        .unwrap_or(0.0)
+}
+
+pub fn get_report(stream1: &VideoBuffer, stream2: &VideoBuffer) -> f64 {
+    get_score(stream1, stream2, &VmafOptions::default())
+}